@@ -1,10 +1,13 @@
+use crate::config::DEFAULT_ANILIST_URL;
+use crate::ratelimit::{RateLimitUsage, RateLimiter};
 use crate::resources::Query;
 use crate::util;
-use anyhow::{anyhow, Result};
-use reqwest::{blocking::Client, StatusCode};
+use reqwest::{header::HeaderMap, Client, StatusCode};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_json::{json, Map, Value};
-// use tokio::time;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 #[derive(Deserialize, Debug)]
 pub struct QueryError {
@@ -12,6 +15,69 @@ pub struct QueryError {
     pub status: Option<i32>,
 }
 
+/// Why an AniList request failed
+#[derive(Debug)]
+pub enum AniListError {
+    /// Still throttled after every retry
+    RateLimited,
+    /// The user, activity or media asked about doesn't exist (any more)
+    NotFound(String),
+    /// The user keeps their list private
+    PrivateList(String),
+    /// AniList is having trouble, so trying again later may work
+    Server(String),
+    /// AniList rejected the query or answered with something unexpected, so retrying won't help
+    InvalidQuery(String),
+    /// The request could not be sent or its response could not be read
+    Request(reqwest::Error),
+}
+
+pub type AniListResult<T> = std::result::Result<T, AniListError>;
+
+impl AniListError {
+    /// Classifies an entry of a GraphQL response's `errors`
+    fn from_query_error(error: &QueryError, http_status: StatusCode) -> Self {
+        let message = error.message.clone().unwrap_or_else(|| "no message given".to_string());
+        if message.to_lowercase().contains("private") {
+            return AniListError::PrivateList(message);
+        }
+        match error.status.map(|status| status as u16).unwrap_or_else(|| http_status.as_u16()) {
+            429 => AniListError::RateLimited,
+            404 => AniListError::NotFound(message),
+            500..=599 => AniListError::Server(message),
+            _ => AniListError::InvalidQuery(message),
+        }
+    }
+}
+
+impl fmt::Display for AniListError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AniListError::RateLimited => write!(f, "rate limited by AniList"),
+            AniListError::NotFound(message) => write!(f, "not found: {}", message),
+            AniListError::PrivateList(message) => write!(f, "private list: {}", message),
+            AniListError::Server(message) => write!(f, "AniList server error: {}", message),
+            AniListError::InvalidQuery(message) => write!(f, "invalid query: {}", message),
+            AniListError::Request(err) => write!(f, "request failed: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for AniListError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            AniListError::Request(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for AniListError {
+    fn from(err: reqwest::Error) -> Self {
+        AniListError::Request(err)
+    }
+}
+
 #[derive(Deserialize, Debug)]
 pub struct QueryResponse<R> {
     pub data: Option<R>,
@@ -21,7 +87,10 @@ pub struct QueryResponse<R> {
 #[derive(Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct PageInfo {
-    has_next_page: bool,
+    pub has_next_page: bool,
+    /// Only asked for by queries that need it
+    #[serde(default)]
+    pub last_page: Option<i32>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -33,142 +102,304 @@ pub struct PageResponse<R> {
 #[derive(Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct Page<R> {
-    // pub page_info: PageInfo,
+    pub page_info: PageInfo,
     pub page: R,
 }
 
-pub type Activities = Option<Vec<Activity>>;
-pub type ActivityReplies = Option<Vec<ActivityReply>>;
-pub type MediaListEntries = Option<Vec<MediaList>>;
-pub type Users = Option<Vec<User>>;
-
-pub fn query_from_file<R>(
-    query_path: &str,
-    variables: &Option<Map<String, Value>>,
-) -> Result<QueryResponse<R>>
-where
-    R: DeserializeOwned,
-{
-    let query: String = Query::get(query_path).map_or_else(
-        || Err(anyhow!("could not load query from \"{}\"", query_path)),
-        |query| {
-            std::str::from_utf8(&*query).map_or_else(
-                |err| {
-                    Err(anyhow!(
-                        "failed to covert \"{}\" query to utf8: {}",
-                        query_path,
-                        err
-                    ))
-                },
-                |s| Ok(s.to_string()),
-            )
-        },
-    )?;
-    query_graphql(&query, variables)//.await
-}
-
-pub fn query_graphql<R>(
-    query_str: &str,
-    variables: &Option<Map<String, Value>>,
-) -> Result<QueryResponse<R>>
-where
-    R: DeserializeOwned,
-{
-    let query = if let Some(vars) = &variables {
-        json!({ "query": query_str, "variables": vars })
-    } else {
-        json!({ "query": query_str })
-    };
-
-    let max_rate_limit_count: i32 = 5;
-    for _ in 0..max_rate_limit_count {
-        let client = Client::new();
-        let resp = client
-            .post("https://graphql.anilist.co")
-            .header("Content-Type", "application/json")
-            .json(&query)
-            .send()?;
-            //.await?;
-
-        match resp.status() {
-            StatusCode::TOO_MANY_REQUESTS => {
-                let secs;
-                let retry = resp.headers().get("Retry-After");
-                if let Some(val) = retry {
-                    let header = String::from_utf8_lossy(val.as_bytes());
-                    secs = header.parse::<u64>().unwrap_or(60);
-                } else {
-                    secs = 60;
+/// Items collected by walking a paginated query
+#[derive(Debug)]
+pub struct Paged<T> {
+    pub items: Vec<T>,
+    /// How many pages were fetched
+    pub pages: i32,
+}
+
+/// Requests per minute AniList allows, until its headers say otherwise
+const DEFAULT_RATE_LIMIT: u32 = 90;
+
+/// Most pages of an activity's newest replies fetched (10 replies a page)
+pub const MAX_REPLY_PAGES: i32 = 10;
+/// Most pages of list entries fetched for one user (50 entries a page)
+pub const MAX_LIST_PAGES: i32 = 5;
+
+/// Async AniList GraphQL client that reuses a single connection pool
+#[derive(Clone)]
+pub struct AniListClient {
+    client: Client,
+    endpoint: String,
+    /// When the current rate-limit pause ends, shared by every clone
+    backoff_until: Arc<Mutex<Option<Instant>>>,
+    /// Paces requests so every clone stays within one budget
+    limiter: RateLimiter,
+}
+
+impl AniListClient {
+    pub fn new(endpoint: &str) -> Self {
+        AniListClient {
+            client: Client::new(),
+            endpoint: endpoint.to_string(),
+            backoff_until: Arc::new(Mutex::new(None)),
+            limiter: RateLimiter::new(DEFAULT_RATE_LIMIT, Duration::from_secs(60)),
+        }
+    }
+
+    /// How much of the request budget is left
+    pub fn rate_limit(&self) -> RateLimitUsage {
+        self.limiter.usage()
+    }
+
+    /// Time left before AniList said requests may resume, if it has throttled us
+    pub fn backoff(&self) -> Option<Duration> {
+        let until = (*self.backoff_until.lock().unwrap())?;
+        until.checked_duration_since(Instant::now())
+    }
+
+    pub async fn query_from_file<R>(
+        &self,
+        query_path: &str,
+        variables: &Option<Map<String, Value>>,
+    ) -> AniListResult<QueryResponse<R>>
+    where
+        R: DeserializeOwned,
+    {
+        let query: String = Query::get(query_path).map_or_else(
+            || Err(AniListError::InvalidQuery(format!("could not load query from \"{}\"", query_path))),
+            |query| {
+                std::str::from_utf8(&query).map_or_else(
+                    |err| {
+                        Err(AniListError::InvalidQuery(format!(
+                            "failed to covert \"{}\" query to utf8: {}",
+                            query_path,
+                            err
+                        )))
+                    },
+                    |s| Ok(s.to_string()),
+                )
+            },
+        )?;
+        self.query_graphql(&query, variables).await
+    }
+
+    pub async fn query_graphql<R>(
+        &self,
+        query_str: &str,
+        variables: &Option<Map<String, Value>>,
+    ) -> AniListResult<QueryResponse<R>>
+    where
+        R: DeserializeOwned,
+    {
+        let query = if let Some(vars) = &variables {
+            json!({ "query": query_str, "variables": vars })
+        } else {
+            json!({ "query": query_str })
+        };
+
+        let max_rate_limit_count: i32 = 5;
+        for _ in 0..max_rate_limit_count {
+            self.limiter.acquire().await;
+            let resp = self
+                .client
+                .post(&self.endpoint)
+                .header("Content-Type", "application/json")
+                .json(&query)
+                .send()
+                .await?;
+            self.limiter.update(
+                header_number(resp.headers(), "X-RateLimit-Limit"),
+                header_number(resp.headers(), "X-RateLimit-Remaining"),
+            );
+
+            if resp.status() == StatusCode::TOO_MANY_REQUESTS {
+                self.limiter.drain();
+                let secs = resp
+                    .headers()
+                    .get("Retry-After")
+                    .and_then(|val| String::from_utf8_lossy(val.as_bytes()).parse::<u64>().ok())
+                    .unwrap_or(60);
+                *self.backoff_until.lock().unwrap() = Some(Instant::now() + Duration::from_secs(secs));
+                util::wait(secs).await;
+            } else {
+                let status = resp.status();
+                let body = resp.text().await?;
+                let response: QueryResponse<R> = match serde_json::from_str(&body) {
+                    Ok(response) => response,
+                    // Not JSON at all, such as an error page from a proxy in front of AniList
+                    Err(_) if status.is_server_error() || serde_json::from_str::<Value>(&body).is_err() => {
+                        return Err(AniListError::Server(format!("HTTP {}", status)));
+                    }
+                    Err(err) => {
+                        return Err(AniListError::InvalidQuery(format!(
+                            "unexpected response (HTTP {}): {}",
+                            status, err
+                        )));
+                    }
+                };
+                if let Some(error) = response.errors.iter().flatten().next() {
+                    return Err(AniListError::from_query_error(error, status));
+                }
+                if status.is_server_error() {
+                    return Err(AniListError::Server(format!("HTTP {}", status)));
                 }
-                util::wait(secs);
-                // time::sleep(time::Duration::from_secs(secs))?;//.await; 
-            }
-            StatusCode::OK | _ => {
-                // println!("resp: {}", resp.text().await?);
-                let response: QueryResponse<R> = resp.json()?;//.await?;
                 return Ok(response);
-                // return Err(anyhow!("temp"));
             }
         }
+
+        Err(AniListError::RateLimited)
     }
 
-    Err(anyhow!("Exceeded the maximum rate limit count (5)"))
-}
+    /// Walks a paginated query from page 1 until `hasNextPage` is false or `max_pages`
+    /// pages have been fetched, collecting every item along the way.
+    /// The query must take a `$page` variable and alias its list as `page`.
+    pub async fn query_pages<T>(
+        &self,
+        query_path: &str,
+        variables: Map<String, Value>,
+        max_pages: Option<i32>,
+    ) -> AniListResult<Paged<T>>
+    where
+        T: DeserializeOwned,
+    {
+        self.query_pages_until(query_path, variables, max_pages, |_, _| false).await
+    }
 
-pub fn query_in_media_list(
-    user_id: i32,
-    media_ids: &Vec<i32>,
-) -> Result<QueryResponse<PageResponse<MediaListEntries>>> {
-    let variables = json!({
-        "page": 1,
-        "userId": user_id,
-        "mediaIds": media_ids,
-    });
-    if let serde_json::Value::Object(variables) = variables {
-        query_from_file("in_media_list.gql", &Some(variables))//.await
-    } else {
-        Err(anyhow!("media list query variables was not a json object"))
+    /// Like `query_pages`, but also stops after a page for which `done(page number, page items)` is true
+    pub async fn query_pages_until<T, F>(
+        &self,
+        query_path: &str,
+        variables: Map<String, Value>,
+        max_pages: Option<i32>,
+        done: F,
+    ) -> AniListResult<Paged<T>>
+    where
+        T: DeserializeOwned,
+        F: Fn(i32, &[T]) -> bool,
+    {
+        let mut items = vec![];
+        let mut page = 1;
+        let pages = loop {
+            let data = match self.query_page(query_path, &variables, page).await? {
+                Some(data) => data,
+                None => break page - 1,
+            };
+            let page_items = data.page.unwrap_or_default();
+            let finished = done(page, &page_items);
+            items.extend(page_items);
+            if finished || !data.page_info.has_next_page || matches!(max_pages, Some(max) if page >= max) {
+                break page;
+            }
+            page += 1;
+        };
+        Ok(Paged { items, pages })
     }
-}
 
-pub fn query_activities(page: i32) -> Result<QueryResponse<PageResponse<Activities>>> {
-    let variables = json!({
-        "page": page,
-    });
-    if let serde_json::Value::Object(variables) = variables {
-        query_from_file("activities.gql", &Some(variables))//.await
-    } else {
-        Err(anyhow!("activity query variables was not a json object"))
+    /// Fetches a single page of a paginated query, see `query_pages`
+    async fn query_page<T>(
+        &self,
+        query_path: &str,
+        variables: &Map<String, Value>,
+        page: i32,
+    ) -> AniListResult<Option<Page<Option<Vec<T>>>>>
+    where
+        T: DeserializeOwned,
+    {
+        let mut variables = variables.clone();
+        variables.insert("page".to_string(), json!(page));
+        let resp: QueryResponse<PageResponse<Option<Vec<T>>>> =
+            self.query_from_file(query_path, &Some(variables)).await?;
+        Ok(resp.data.map(|data| data.page))
+    }
+
+    pub async fn query_in_media_list(&self, user_id: i32, media_ids: &[i32]) -> AniListResult<Vec<MediaList>> {
+        let variables = variables(
+            json!({
+                "userId": user_id,
+                "mediaIds": media_ids,
+            }),
+            "media list",
+        )?;
+        Ok(self.query_pages("in_media_list.gql", variables, Some(MAX_LIST_PAGES)).await?.items)
+    }
+
+    /// Fetches `min_pages` pages of the newest activities, then keeps paging back until
+    /// a page reaches `since_id` (the newest activity seen before) or `max_pages` is hit
+    pub async fn query_activities(
+        &self,
+        min_pages: i32,
+        max_pages: i32,
+        since_id: Option<i32>,
+    ) -> AniListResult<Paged<Activity>> {
+        self.query_back_to("activities.gql", min_pages, max_pages, since_id, |activity: &Activity| activity.id)
+            .await
+    }
+
+    /// Fetches the newest `MAX_REPLY_PAGES` pages of an activity's replies, oldest first.
+    /// AniList can't sort replies and lists them oldest first, so the first page says where the last one is.
+    pub async fn query_activity_replies(&self, activity_id: i32) -> AniListResult<Vec<ActivityReply>> {
+        let variables = variables(json!({ "activityId": activity_id }), "activity replies")?;
+        let first = match self.query_page("activity_replies.gql", &variables, 1).await? {
+            Some(first) => first,
+            None => return Ok(vec![]),
+        };
+        let last_page = first.page_info.last_page.unwrap_or(1);
+        let oldest_page = (last_page - MAX_REPLY_PAGES + 1).max(1);
+        let mut replies = vec![];
+        if oldest_page == 1 {
+            replies.extend(first.page.unwrap_or_default());
+        }
+        for page in oldest_page.max(2)..=last_page {
+            if let Some(data) = self.query_page("activity_replies.gql", &variables, page).await? {
+                replies.extend(data.page.unwrap_or_default());
+            }
+        }
+        Ok(replies)
+    }
+
+    /// Fetches `min_pages` pages of the newest users, then keeps paging back until
+    /// a page reaches `since_id` (the newest user seen before) or `max_pages` is hit
+    pub async fn query_users(&self, min_pages: i32, max_pages: i32, since_id: Option<i32>) -> AniListResult<Paged<User>> {
+        self.query_back_to("users.gql", min_pages, max_pages, since_id, |user: &User| user.id)
+            .await
+    }
+
+    /// Pages through a query sorted by `ID_DESC` back to `since_id`, see `query_activities`
+    async fn query_back_to<T>(
+        &self,
+        query_path: &str,
+        min_pages: i32,
+        max_pages: i32,
+        since_id: Option<i32>,
+        id: fn(&T) -> i32,
+    ) -> AniListResult<Paged<T>>
+    where
+        T: DeserializeOwned,
+    {
+        let max_pages = if since_id.is_some() { max_pages.max(min_pages) } else { min_pages };
+        self.query_pages_until(query_path, Map::new(), Some(max_pages), |page, items: &[T]| {
+            page >= min_pages
+                && match since_id {
+                    Some(since_id) => items.iter().any(|item| id(item) <= since_id),
+                    None => true,
+                }
+        })
+        .await
     }
 }
 
-pub fn query_activity_replies(
-    page: i32,
-    activity_id: i32,
-) -> Result<QueryResponse<PageResponse<ActivityReplies>>> {
-    let variables = json!({
-        "page": page,
-        "activityId": activity_id,
-    });
-    if let serde_json::Value::Object(variables) = variables {
-        query_from_file("activity_replies.gql", &Some(variables))//.await
-    } else {
-        Err(anyhow!(
-            "activity replies query variables was not a json object"
-        ))
+impl Default for AniListClient {
+    fn default() -> Self {
+        AniListClient::new(DEFAULT_ANILIST_URL)
     }
 }
 
-pub fn query_users(page: i32) -> Result<QueryResponse<PageResponse<Users>>> {
-    let variables = json!({
-        "page": page,
-    });
-    if let serde_json::Value::Object(variables) = variables {
-        query_from_file("users.gql", &Some(variables))//.await
+fn header_number(headers: &HeaderMap, name: &str) -> Option<u32> {
+    headers.get(name)?.to_str().ok()?.trim().parse().ok()
+}
+
+fn variables(variables: Value, name: &str) -> AniListResult<Map<String, Value>> {
+    if let Value::Object(variables) = variables {
+        Ok(variables)
     } else {
-        Err(anyhow!(
-            "users query variables was not a json object"
-        ))
+        Err(AniListError::InvalidQuery(format!("{} query variables was not a json object", name)))
     }
 }
 
@@ -188,6 +419,10 @@ pub struct Activity {
     pub user: User,
     pub recipient: Option<User>,
     pub text: String,
+    #[serde(default)]
+    pub reply_count: i32,
+    /// Unix timestamp the activity was posted at
+    pub created_at: Option<i64>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -202,6 +437,15 @@ pub struct User {
     pub id: i32,
     pub name: String,
     pub about: Option<String>,
+    /// Unix timestamp the account was made at
+    pub created_at: Option<i64>,
+    pub avatar: Option<UserAvatar>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct UserAvatar {
+    pub medium: Option<String>,
+    pub large: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -211,4 +455,6 @@ pub struct ActivityReply {
     pub activity_id: i32,
     pub user: User,
     pub text: String,
+    /// Unix timestamp the reply was posted at
+    pub created_at: Option<i64>,
 }
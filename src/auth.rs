@@ -0,0 +1,102 @@
+use std::collections::BTreeSet;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Who may run commands, and where. Empty sets place no restriction.
+#[derive(Debug, Clone, Default)]
+pub struct Allowlist {
+    pub user_ids: BTreeSet<u64>,
+    pub role_ids: BTreeSet<u64>,
+    pub channel_ids: BTreeSet<u64>,
+}
+
+/// Why a command was refused
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Denial {
+    Channel,
+    DirectMessage,
+    User,
+}
+
+/// Where a command was sent
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Origin {
+    Channel(u64),
+    /// A DM. `dm_only` marks commands that are only accepted in DMs, such as `!webhook add`.
+    Direct { dm_only: bool },
+}
+
+impl Allowlist {
+    /// Whether users are restricted at all; without this anyone may run commands
+    pub fn restricts_users(&self) -> bool {
+        !self.user_ids.is_empty() || !self.role_ids.is_empty()
+    }
+
+    /// Whether roles need to be looked up to decide on a user who is not listed directly
+    pub fn needs_roles(&self, user_id: u64) -> bool {
+        !self.role_ids.is_empty() && !self.user_ids.contains(&user_id)
+    }
+
+    /// The channel must be allowed, and the user must be allowed directly or through a role.
+    /// DMs have no channel to check, so they are only accepted from users allowed by ID,
+    /// apart from DM-only commands when users are restricted, and from anyone when nothing is.
+    pub fn check(&self, user_id: u64, role_ids: &[u64], origin: Origin) -> Result<(), Denial> {
+        match origin {
+            Origin::Channel(channel_id) => {
+                if !self.channel_ids.is_empty() && !self.channel_ids.contains(&channel_id) {
+                    return Err(Denial::Channel);
+                }
+            }
+            Origin::Direct { dm_only } if dm_only && self.restricts_users() => {}
+            Origin::Direct { .. } => {
+                let open = !self.restricts_users() && self.channel_ids.is_empty();
+                if !open && !self.user_ids.contains(&user_id) {
+                    return Err(Denial::DirectMessage);
+                }
+            }
+        }
+        if !self.restricts_users()
+            || self.user_ids.contains(&user_id)
+            || role_ids.iter().any(|role| self.role_ids.contains(role))
+        {
+            Ok(())
+        } else {
+            Err(Denial::User)
+        }
+    }
+}
+
+/// Parses a comma separated list of Discord IDs, panicking on anything else
+/// so a typo can never silently widen access
+pub fn parse_ids(var: &str, value: &str) -> BTreeSet<u64> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+        .map(|id| {
+            id.parse::<u64>()
+                .unwrap_or_else(|_| panic!("{} must be a comma separated list of IDs, got \"{}\"", var, id))
+        })
+        .collect()
+}
+
+/// Appends a line to `audit.log` in `data_dir`, prefixed with the unix time
+pub fn audit(data_dir: &Path, line: &str) {
+    println!("audit: {}", line);
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or_default();
+    let result = fs::create_dir_all(data_dir).and_then(|_| {
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(data_dir.join("audit.log"))
+            .and_then(|mut file| writeln!(file, "{} {}", secs, line))
+    });
+    if let Err(err) = result {
+        println!("could not write audit log: {:?}", err);
+    }
+}
@@ -0,0 +1,404 @@
+use crate::tasks::TaskId;
+use crate::webhooks::WebhookJob;
+use serde_json::{error::Category, json, Map, Value};
+use std::fmt;
+
+pub const WATCH_USAGE: &str = "Usage: `!watch activities keywords=\"a,b\" webhook=<name> [replies=true] [depth=<pages>] \
+[interval=<seconds>] [jitter=<seconds>] [normalize=true] [user-keywords=\"c,d\"] [report-score=<score>] \
+[escalate-score=<score>]`";
+pub const SWEEP_USAGE: &str = "Usage: `!sweep users keywords=\"a,b\" webhook=<name> depth=<pages> [media=121,999] \
+[max-score=<score>] [full-sweep=true] [interval=<seconds>] [jitter=<seconds>] [normalize=true] \
+[report-score=<score>] [escalate-score=<score>]`";
+const KEYWORD_HELP: &str = "Keywords are comma separated; prefix one with `word:` to match whole words only \
+or `regex:` for a regular expression, which takes the rest of the value, commas included.";
+
+/// Whether `cmd`, the first word of a message, is one of the bot's own commands
+pub fn is_command(cmd: &str) -> bool {
+    cmd.starts_with("!start-task")
+        || cmd.starts_with("!stop-task")
+        || matches!(cmd, "!watch" | "!sweep" | "!status" | "!webhook")
+}
+
+/// Embed color for command errors
+pub const ERROR_COLOR: u64 = 0xE74C3C;
+
+/// What to send back for a command, whether it came from a message or a slash command
+#[derive(Debug, Clone, PartialEq)]
+pub enum Reply {
+    Info(String),
+    Error { title: String, description: String },
+}
+
+impl Reply {
+    pub fn error(title: &str, description: impl Into<String>) -> Self {
+        Reply::Error {
+            title: title.to_string(),
+            description: description.into(),
+        }
+    }
+
+    /// The reply as a Discord embed object
+    pub fn embed(&self) -> Value {
+        match self {
+            Reply::Info(description) => json!({ "description": description }),
+            Reply::Error { title, description } => json!({
+                "title": title,
+                "description": description,
+                "color": ERROR_COLOR,
+            }),
+        }
+    }
+}
+
+/// Which tasks `!stop-task` or `/raid stop` should stop
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StopTarget {
+    One(TaskId),
+    All,
+}
+
+impl StopTarget {
+    /// Reads `<id>` or `all`
+    pub fn parse(arg: &str) -> Option<Self> {
+        match arg.trim() {
+            "all" => Some(StopTarget::All),
+            arg => arg.parse().ok().map(StopTarget::One),
+        }
+    }
+}
+
+/// A command that could not be understood, along with how it should have been written
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommandError {
+    pub message: String,
+    pub usage: &'static str,
+}
+
+impl CommandError {
+    pub fn new(message: impl Into<String>, usage: &'static str) -> Self {
+        CommandError {
+            message: message.into(),
+            usage,
+        }
+    }
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}\n{}\n{}", self.message, self.usage, KEYWORD_HELP)
+    }
+}
+
+impl std::error::Error for CommandError {}
+
+/// Builds a job from `!watch activities ...` arguments
+pub fn parse_watch(args: &str) -> Result<WebhookJob, CommandError> {
+    let (subject, options) = split_subject(args, WATCH_USAGE)?;
+    watch_job(&subject, options)
+}
+
+/// Builds a job from `!sweep users ...` arguments
+pub fn parse_sweep(args: &str) -> Result<WebhookJob, CommandError> {
+    let (subject, options) = split_subject(args, SWEEP_USAGE)?;
+    sweep_job(&subject, options)
+}
+
+/// Builds an activity job from already split `key=value` options
+pub fn watch_job(subject: &str, options: Vec<(String, String)>) -> Result<WebhookJob, CommandError> {
+    let usage = WATCH_USAGE;
+    if subject != "activities" {
+        return Err(CommandError::new(format!("Can't watch \"{}\", only activities.", subject), usage));
+    }
+
+    let mut job = Map::new();
+    let mut user_keywords = None;
+    let mut destination = Map::new();
+    for (key, value) in options {
+        match key.as_str() {
+            "keywords" => {
+                job.insert("keywords".to_string(), keywords(&value));
+            }
+            "replies" => {
+                job.insert("replies".to_string(), json!(boolean(&key, &value, usage)?));
+            }
+            "depth" => {
+                job.insert("depth".to_string(), json!(number(&key, &value, usage)?));
+            }
+            "interval" | "jitter" => {
+                job.insert(key.clone(), json!(number(&key, &value, usage)?));
+            }
+            "normalize" => {
+                job.insert("normalize".to_string(), json!(boolean(&key, &value, usage)?));
+            }
+            "report-score" | "escalate-score" => threshold(&mut job, &key, &value, usage)?,
+            "user-keywords" => user_keywords = Some(keywords(&value)),
+            _ => destination_option(&mut destination, &key, value, usage)?,
+        }
+    }
+    if !job.contains_key("keywords") {
+        return Err(CommandError::new("`keywords` is required.", usage));
+    }
+    if let Some(user_keywords) = user_keywords {
+        let normalize = job.get("normalize").cloned().unwrap_or(json!(false));
+        job.insert(
+            "user_job".to_string(),
+            json!({
+                "keywords": user_keywords,
+                "mediaIds": [],
+                "depth": 1,
+                "normalize": normalize,
+            }),
+        );
+    }
+
+    build("Activity", destination, job, usage)
+}
+
+/// Builds a user job from already split `key=value` options
+pub fn sweep_job(subject: &str, options: Vec<(String, String)>) -> Result<WebhookJob, CommandError> {
+    let usage = SWEEP_USAGE;
+    if subject != "users" {
+        return Err(CommandError::new(format!("Can't sweep \"{}\", only users.", subject), usage));
+    }
+
+    let mut job = Map::new();
+    job.insert("keywords".to_string(), json!([]));
+    job.insert("mediaIds".to_string(), json!([]));
+    let mut destination = Map::new();
+    for (key, value) in options {
+        match key.as_str() {
+            "keywords" => {
+                job.insert("keywords".to_string(), keywords(&value));
+            }
+            "depth" => {
+                job.insert("depth".to_string(), json!(number(&key, &value, usage)?));
+            }
+            "media" => {
+                let media_ids = split_list(&value)
+                    .map(|id| number("media", id, usage))
+                    .collect::<Result<Vec<_>, _>>()?;
+                job.insert("mediaIds".to_string(), json!(media_ids));
+            }
+            "interval" | "jitter" => {
+                job.insert(key.clone(), json!(number(&key, &value, usage)?));
+            }
+            "full-sweep" => {
+                job.insert("fullSweep".to_string(), json!(boolean(&key, &value, usage)?));
+            }
+            "max-score" => {
+                job.insert("maxScoreThreshold".to_string(), json!(number(&key, &value, usage)?));
+            }
+            "normalize" => {
+                job.insert("normalize".to_string(), json!(boolean(&key, &value, usage)?));
+            }
+            "report-score" | "escalate-score" => threshold(&mut job, &key, &value, usage)?,
+            _ => destination_option(&mut destination, &key, value, usage)?,
+        }
+    }
+    if !job.contains_key("depth") {
+        return Err(CommandError::new("`depth` is required.", usage));
+    }
+
+    build("User", destination, job, usage)
+}
+
+fn build(
+    kind: &str,
+    mut destination: Map<String, Value>,
+    job: Map<String, Value>,
+    usage: &'static str,
+) -> Result<WebhookJob, CommandError> {
+    if destination.is_empty() {
+        return Err(CommandError::new("`webhook` is required.", usage));
+    }
+    destination.insert("job".to_string(), Value::Object(job));
+    serde_json::from_value(json!({ kind: destination }))
+        .map_err(|err| CommandError::new(format!("Invalid job: {}", err), usage))
+}
+
+/// The webhook options shared by every command
+fn destination_option(
+    destination: &mut Map<String, Value>,
+    key: &str,
+    value: String,
+    usage: &'static str,
+) -> Result<(), CommandError> {
+    let field = match key {
+        "webhook" => "webhook",
+        "channel-id" => "channelId",
+        "token" => "token",
+        _ => return Err(CommandError::new(format!("Unknown option `{}`.", key), usage)),
+    };
+    destination.insert(field.to_string(), json!(value));
+    Ok(())
+}
+
+/// Splits the leading subject (e.g. `activities`) from its `key=value` options
+fn split_subject(args: &str, usage: &'static str) -> Result<(String, Vec<(String, String)>), CommandError> {
+    let mut tokens = tokenize(args).map_err(|message| CommandError::new(message, usage))?.into_iter();
+    let subject = tokens
+        .next()
+        .ok_or_else(|| CommandError::new("Missing what to look through.", usage))?;
+    let options = tokens
+        .map(|token| match token.find('=') {
+            Some(index) => Ok((token[..index].to_lowercase(), token[index + 1..].to_string())),
+            None => Err(CommandError::new(
+                format!("Expected `key=value` but got `{}`.", token),
+                usage,
+            )),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok((subject.to_lowercase(), options))
+}
+
+/// Splits on whitespace, keeping double quoted sections (which may contain `\"`) together
+fn tokenize(input: &str) -> Result<Vec<String>, String> {
+    let mut tokens = vec![];
+    let mut token = String::new();
+    let mut in_token = false;
+    let mut quoted = false;
+    let mut chars = input.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                in_token = true;
+            }
+            '\\' if quoted => match chars.next() {
+                Some(escaped) => token.push(escaped),
+                None => return Err("Unfinished escape at the end of the command.".to_string()),
+            },
+            c if c.is_whitespace() && !quoted => {
+                if in_token {
+                    tokens.push(std::mem::take(&mut token));
+                    in_token = false;
+                }
+            }
+            c => {
+                token.push(c);
+                in_token = true;
+            }
+        }
+    }
+    if quoted {
+        return Err("A quote was never closed.".to_string());
+    }
+    if in_token {
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+fn split_list(value: &str) -> impl Iterator<Item = &str> {
+    value.split(',').map(str::trim).filter(|item| !item.is_empty())
+}
+
+/// Turns `spam,word:the,regex:free\s+nitro` into the job JSON keyword forms.
+/// A `regex:` keyword takes the rest of the value, so commas in `{3,}` and the like survive.
+fn keywords(value: &str) -> Value {
+    let mut keywords = vec![];
+    let mut rest = value;
+    while !rest.is_empty() {
+        let (item, next) = match rest.split_once(',') {
+            Some((item, next)) if !item.trim_start().starts_with("regex:") => (item, next),
+            _ => (rest, ""),
+        };
+        rest = next;
+        let keyword = item.trim();
+        if let Some(word) = keyword.strip_prefix("word:") {
+            keywords.push(json!({ "word": word }));
+        } else if let Some(regex) = keyword.strip_prefix("regex:") {
+            keywords.push(json!({ "regex": regex }));
+        } else if !keyword.is_empty() {
+            keywords.push(json!(keyword));
+        }
+    }
+    Value::Array(keywords)
+}
+
+/// Reads a `report-score` or `escalate-score` into the job's `reportScore` or `escalateScore`
+fn threshold(job: &mut Map<String, Value>, key: &str, value: &str, usage: &'static str) -> Result<(), CommandError> {
+    let field = if key == "report-score" { "reportScore" } else { "escalateScore" };
+    job.insert(field.to_string(), json!(number(key, value, usage)?));
+    Ok(())
+}
+
+fn number(key: &str, value: &str, usage: &'static str) -> Result<i32, CommandError> {
+    value
+        .parse::<i32>()
+        .map_err(|_| CommandError::new(format!("`{}` must be a whole number, got `{}`.", key, value), usage))
+}
+
+fn boolean(key: &str, value: &str, usage: &'static str) -> Result<bool, CommandError> {
+    match value.to_lowercase().as_str() {
+        "true" | "yes" | "on" => Ok(true),
+        "false" | "no" | "off" => Ok(false),
+        _ => Err(CommandError::new(
+            format!("`{}` must be true or false, got `{}`.", key, value),
+            usage,
+        )),
+    }
+}
+
+/// Explains why a `!start-task` body could not be read as a job:
+/// where parsing stopped, the offending line, and the field being read at the time
+pub fn describe_json_error(body: &str, err: &serde_json::Error) -> String {
+    let message = err.to_string();
+    let message = match message.rfind(" at line ") {
+        Some(index) => &message[..index],
+        None => message.as_str(),
+    };
+    let mut lines = vec![format!(
+        "Line {}, column {}: {}",
+        err.line(),
+        err.column(),
+        message
+    )];
+
+    let line = err.line().saturating_sub(1);
+    if let Some(text) = body.lines().nth(line) {
+        let caret = " ".repeat(text[..char_boundary(text, err.column())].chars().count().saturating_sub(1));
+        lines.push(format!("```\n{}\n{}^\n```", text, caret));
+    }
+
+    let hint = match err.classify() {
+        Category::Eof => Some("The job ends early, check for a missing closing brace, bracket or quote.".to_string()),
+        Category::Syntax => Some("Check for a missing comma or quote, or a trailing comma, just before here.".to_string()),
+        // Messages like "missing field `depth`" already name the field
+        Category::Data if message.contains("field `") => None,
+        Category::Data => last_field(body, line, err.column())
+            .map(|field| format!("Check the value given for `{}`.", field)),
+        Category::Io => None,
+    };
+    lines.extend(hint);
+    lines.join("\n")
+}
+
+/// `index` moved back to the start of the character it falls in, since serde_json's columns
+/// count bytes and can point into the middle of one
+fn char_boundary(text: &str, index: usize) -> usize {
+    let mut index = index.min(text.len());
+    while !text.is_char_boundary(index) {
+        index -= 1;
+    }
+    index
+}
+
+/// The last `"key":` written before the given zero based line and one based column
+fn last_field(body: &str, line: usize, column: usize) -> Option<String> {
+    let offset = body
+        .split_inclusive('\n')
+        .take(line)
+        .map(str::len)
+        .sum::<usize>()
+        + column;
+    let before = &body[..char_boundary(body, offset)];
+    let mut field = None;
+    let mut rest = before;
+    while let Some(end) = rest.find("\":") {
+        let key = &rest[..end];
+        field = key.rfind('"').map(|start| key[start + 1..].to_string());
+        rest = &rest[end + 2..];
+    }
+    field
+}
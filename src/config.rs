@@ -0,0 +1,63 @@
+use crate::auth::{parse_ids, Allowlist};
+use std::collections::BTreeSet;
+use std::path::PathBuf;
+
+pub const DEFAULT_ANILIST_URL: &str = "https://graphql.anilist.co";
+pub const DEFAULT_WEBHOOK_URL: &str = "https://discord.com/api/v8/webhooks";
+pub const DEFAULT_DATA_DIR: &str = "data";
+pub const DEFAULT_DISCORD_API_URL: &str = "https://discord.com/api/v8";
+
+/// Settings read from the environment (or `.env`)
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// GraphQL endpoint queried for activities, users and lists (`ANILIST_URL`)
+    pub anilist_url: String,
+    /// Base URL that webhook IDs and tokens are appended to (`WEBHOOK_URL`)
+    pub webhook_url: String,
+    /// Directory the bot keeps its state in (`DATA_DIR`)
+    pub data_dir: PathBuf,
+    /// Who may run commands (`ALLOWED_USER_IDS`, `ALLOWED_ROLE_IDS`, `ALLOWED_CHANNEL_IDS`)
+    pub allowlist: Allowlist,
+    /// Discord REST API that slash commands are registered and answered through (`DISCORD_API_URL`)
+    pub discord_api_url: String,
+    /// Bot application ID, slash commands are disabled without it (`APPLICATION_ID`)
+    pub application_id: Option<String>,
+    /// Server to register slash commands in instead of globally (`COMMAND_GUILD_ID`)
+    pub command_guild_id: Option<String>,
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        Config {
+            anilist_url: dotenv::var("ANILIST_URL").unwrap_or_else(|_| DEFAULT_ANILIST_URL.to_string()),
+            webhook_url: dotenv::var("WEBHOOK_URL").unwrap_or_else(|_| DEFAULT_WEBHOOK_URL.to_string()),
+            data_dir: dotenv::var("DATA_DIR").unwrap_or_else(|_| DEFAULT_DATA_DIR.to_string()).into(),
+            allowlist: Allowlist {
+                user_ids: ids_from_env("ALLOWED_USER_IDS"),
+                role_ids: ids_from_env("ALLOWED_ROLE_IDS"),
+                channel_ids: ids_from_env("ALLOWED_CHANNEL_IDS"),
+            },
+            discord_api_url: dotenv::var("DISCORD_API_URL").unwrap_or_else(|_| DEFAULT_DISCORD_API_URL.to_string()),
+            application_id: dotenv::var("APPLICATION_ID").ok(),
+            command_guild_id: dotenv::var("COMMAND_GUILD_ID").ok(),
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            anilist_url: DEFAULT_ANILIST_URL.to_string(),
+            webhook_url: DEFAULT_WEBHOOK_URL.to_string(),
+            data_dir: DEFAULT_DATA_DIR.into(),
+            allowlist: Allowlist::default(),
+            discord_api_url: DEFAULT_DISCORD_API_URL.to_string(),
+            application_id: None,
+            command_guild_id: None,
+        }
+    }
+}
+
+fn ids_from_env(var: &str) -> BTreeSet<u64> {
+    dotenv::var(var).map(|ids| parse_ids(var, &ids)).unwrap_or_default()
+}
@@ -1,20 +1,67 @@
 use serde::Serialize;
+use std::fmt;
+use std::ops::Range;
 
-#[derive(Serialize, Clone)]
+/// Most embeds Discord accepts in one message
+pub const MAX_EMBEDS_PER_MESSAGE: usize = 10;
+/// Most characters Discord accepts across every embed in one message
+pub const MAX_MESSAGE_TEXT: usize = 6000;
+/// Discord's limits on the parts of a single embed, in characters
+pub const MAX_TITLE_LEN: usize = 256;
+pub const MAX_DESCRIPTION_LEN: usize = 4096;
+pub const MAX_FIELDS: usize = 25;
+pub const MAX_FIELD_NAME_LEN: usize = 256;
+pub const MAX_FIELD_VALUE_LEN: usize = 1024;
+pub const MAX_AUTHOR_NAME_LEN: usize = 256;
+pub const MAX_FOOTER_LEN: usize = 2048;
+
+/// Characters of surrounding text shown on each side of a match
+const SNIPPET_CONTEXT: usize = 60;
+/// Longest match shown in full in a snippet
+const MAX_SNIPPET_MATCH: usize = 200;
+
+#[derive(Serialize, Clone, Default)]
 pub struct Embed {
     pub title: String,
-    pub fields: Vec<EmbedField>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
     pub url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub color: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub author: Option<EmbedAuthor>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thumbnail: Option<EmbedImage>,
+    /// ISO 8601, which Discord shows in the reader's timezone
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub footer: Option<EmbedFooter>,
+    pub fields: Vec<EmbedField>,
 }
 
 impl Embed {
     pub fn from(url: String, title: String) -> Self {
         Embed {
-            title,
+            title: truncate(&title, MAX_TITLE_LEN),
             url,
-            fields: vec![],
+            ..Embed::default()
         }
     }
+
+    /// Characters that count towards `MAX_MESSAGE_TEXT`
+    pub fn text_len(&self) -> usize {
+        let len = |text: &str| text.chars().count();
+        len(&self.title)
+            + self.description.as_deref().map_or(0, len)
+            + self.author.as_ref().map_or(0, |author| len(&author.name))
+            + self.footer.as_ref().map_or(0, |footer| len(&footer.text))
+            + self
+                .fields
+                .iter()
+                .map(|field| len(&field.name) + len(&field.value))
+                .sum::<usize>()
+    }
 }
 
 #[derive(Serialize, Clone)]
@@ -26,8 +73,107 @@ pub struct EmbedField {
 impl EmbedField {
     pub fn from(name: String, value: String) -> Self {
         EmbedField {
-            name,
-            value
+            name: truncate(&name, MAX_FIELD_NAME_LEN),
+            value: truncate(&value, MAX_FIELD_VALUE_LEN),
+        }
+    }
+}
+
+#[derive(Serialize, Clone)]
+pub struct EmbedAuthor {
+    pub name: String,
+    pub url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub icon_url: Option<String>,
+}
+
+#[derive(Serialize, Clone)]
+pub struct EmbedImage {
+    pub url: String,
+}
+
+#[derive(Serialize, Clone)]
+pub struct EmbedFooter {
+    pub text: String,
+}
+
+/// How serious a hit looks, which picks the color of its embed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Low,
+    Medium,
+    High,
+}
+
+impl Severity {
+    pub fn color(self) -> u64 {
+        match self {
+            Severity::Low => 0xF1C40F,
+            Severity::Medium => 0xE67E22,
+            Severity::High => 0xE74C3C,
+        }
+    }
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Low => write!(f, "low"),
+            Severity::Medium => write!(f, "medium"),
+            Severity::High => write!(f, "high"),
+        }
+    }
+}
+
+/// Cuts `text` down to `max` characters, ending it with an ellipsis if anything was cut
+pub fn truncate(text: &str, max: usize) -> String {
+    if text.chars().count() <= max {
+        text.to_string()
+    } else {
+        let mut truncated = text.chars().take(max.saturating_sub(1)).collect::<String>();
+        truncated.push('…');
+        truncated
+    }
+}
+
+/// Escapes the characters Discord treats as markdown, so user text shows as written
+pub fn escape_markdown(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '\\' | '*' | '_' | '~' | '`' | '|' | '>') {
+            escaped.push('\\');
         }
+        escaped.push(c);
     }
-}
\ No newline at end of file
+    escaped
+}
+
+/// The part of `text` around `matched` (a byte range), on one line,
+/// with the match in bold and ellipses wherever text was left out
+pub fn snippet(text: &str, matched: Range<usize>) -> String {
+    let flatten = |part: &str| part.split_whitespace().collect::<Vec<_>>().join(" ");
+    let before = flatten(&text[..matched.start]);
+    let after = flatten(&text[matched.end..]);
+    let found = truncate(&flatten(&text[matched.clone()]), MAX_SNIPPET_MATCH);
+
+    let before_len = before.chars().count();
+    let mut snippet = String::new();
+    if before_len > SNIPPET_CONTEXT {
+        snippet.push('…');
+    }
+    let before = before.chars().skip(before_len.saturating_sub(SNIPPET_CONTEXT)).collect::<String>();
+    snippet.push_str(&escape_markdown(&before));
+    // Keep the space between the match and the words around it
+    if !before.is_empty() && text[..matched.start].ends_with(char::is_whitespace) {
+        snippet.push(' ');
+    }
+    snippet.push_str(&format!("**{}**", escape_markdown(&found)));
+    if !after.is_empty() && text[matched.end..].starts_with(char::is_whitespace) {
+        snippet.push(' ');
+    }
+    snippet.push_str(&escape_markdown(&after.chars().take(SNIPPET_CONTEXT).collect::<String>()));
+    if after.chars().count() > SNIPPET_CONTEXT {
+        snippet.push('…');
+    }
+    snippet
+}
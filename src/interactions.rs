@@ -0,0 +1,319 @@
+use crate::commands::{self, CommandError, Reply, StopTarget};
+use crate::webhooks::WebhookJob;
+use anyhow::{anyhow, Result};
+use reqwest::Client;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+/// Interaction type sent when someone runs a slash command
+const APPLICATION_COMMAND: u8 = 2;
+/// Callback type answering an interaction with a message
+const CHANNEL_MESSAGE_WITH_SOURCE: u8 = 4;
+/// Callback type acknowledging an interaction now and answering it later with `edit_response`
+const DEFERRED_CHANNEL_MESSAGE_WITH_SOURCE: u8 = 5;
+
+/// Option types used by `/raid`
+const SUB_COMMAND: u8 = 1;
+const STRING: u8 = 3;
+const INTEGER: u8 = 4;
+const BOOLEAN: u8 = 5;
+
+/// An `INTERACTION_CREATE` gateway event
+#[derive(Deserialize, Debug)]
+pub struct Interaction {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub kind: u8,
+    pub token: String,
+    pub channel_id: Option<String>,
+    /// Unset when the command was run in a DM
+    pub guild_id: Option<String>,
+    /// Set when the command was run in a server
+    pub member: Option<InteractionMember>,
+    /// Set when the command was run in a DM
+    pub user: Option<InteractionUser>,
+    pub data: Option<CommandData>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct InteractionMember {
+    pub user: InteractionUser,
+    #[serde(default)]
+    pub roles: Vec<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct InteractionUser {
+    pub id: String,
+    pub username: String,
+    pub discriminator: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CommandData {
+    pub name: String,
+    #[serde(default)]
+    pub options: Vec<CommandOption>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CommandOption {
+    pub name: String,
+    pub value: Option<Value>,
+    /// A subcommand's own options
+    #[serde(default)]
+    pub options: Vec<CommandOption>,
+}
+
+/// What a `/raid` interaction asks for
+pub enum RaidCommand {
+    Start(Box<WebhookJob>),
+    /// `None` when neither a task ID nor `all` was given
+    Stop(Option<StopTarget>),
+    Status,
+}
+
+impl Interaction {
+    /// Whether this is a slash command for this bot, rather than e.g. a button press
+    pub fn is_raid_command(&self) -> bool {
+        self.kind == APPLICATION_COMMAND && matches!(&self.data, Some(data) if data.name == "raid")
+    }
+
+    pub fn user(&self) -> Option<&InteractionUser> {
+        self.member.as_ref().map(|member| &member.user).or(self.user.as_ref())
+    }
+
+    pub fn role_ids(&self) -> Vec<u64> {
+        self.member
+            .iter()
+            .flat_map(|member| member.roles.iter())
+            .filter_map(|role| role.parse().ok())
+            .collect()
+    }
+
+    pub fn channel_id(&self) -> Option<u64> {
+        self.channel_id.as_ref().and_then(|id| id.parse().ok())
+    }
+
+    /// The command as typed, e.g. `/raid start`, for the audit log
+    pub fn command_name(&self) -> String {
+        let data = match &self.data {
+            Some(data) => data,
+            None => return "/?".to_string(),
+        };
+        match data.options.first() {
+            Some(subcommand) => format!("/{} {}", data.name, subcommand.name),
+            None => format!("/{}", data.name),
+        }
+    }
+
+    /// Reads the `/raid` subcommand and its options
+    pub fn command(&self) -> Result<RaidCommand, CommandError> {
+        let subcommand = self
+            .data
+            .as_ref()
+            .and_then(|data| data.options.first())
+            .ok_or_else(|| CommandError::new("Missing a subcommand.", RAID_USAGE))?;
+        let mut options = subcommand
+            .options
+            .iter()
+            .filter_map(|option| option.value.as_ref().map(|value| (option.name.clone(), option_text(value))))
+            .collect::<Vec<_>>();
+        match subcommand.name.as_str() {
+            "start" => {
+                let target = match options.iter().position(|(name, _)| name == "target") {
+                    Some(index) => options.remove(index).1,
+                    None => return Err(CommandError::new("`target` is required.", RAID_USAGE)),
+                };
+                let job = if target == "users" {
+                    commands::sweep_job(&target, options)?
+                } else {
+                    commands::watch_job(&target, options)?
+                };
+                Ok(RaidCommand::Start(Box::new(job)))
+            }
+            "stop" => {
+                let all = options.iter().any(|(name, value)| name == "all" && value == "true");
+                let id = options.iter().find(|(name, _)| name == "id").map(|(_, id)| id.as_str());
+                Ok(RaidCommand::Stop(if all {
+                    Some(StopTarget::All)
+                } else {
+                    id.and_then(StopTarget::parse)
+                }))
+            }
+            "status" => Ok(RaidCommand::Status),
+            other => Err(CommandError::new(format!("Unknown subcommand `{}`.", other), RAID_USAGE)),
+        }
+    }
+}
+
+const RAID_USAGE: &str = "Usage: `/raid start`, `/raid stop` or `/raid status`";
+
+fn option_text(value: &Value) -> String {
+    match value {
+        Value::String(text) => text.clone(),
+        other => other.to_string(),
+    }
+}
+
+/// Registers slash commands and answers interactions over Discord's REST API
+#[derive(Clone)]
+pub struct InteractionsClient {
+    client: Client,
+    api_url: String,
+    application_id: String,
+    bot_token: String,
+}
+
+impl InteractionsClient {
+    pub fn new(api_url: &str, application_id: &str, bot_token: &str) -> Self {
+        InteractionsClient {
+            client: Client::new(),
+            api_url: api_url.trim_end_matches('/').to_string(),
+            application_id: application_id.to_string(),
+            bot_token: bot_token.to_string(),
+        }
+    }
+
+    /// Creates or updates `/raid`, in one server if `guild_id` is given (which applies
+    /// immediately) or globally otherwise (which can take up to an hour to show up)
+    pub async fn register_commands(&self, guild_id: Option<&str>) -> Result<()> {
+        let url = match guild_id {
+            Some(guild_id) => format!(
+                "{}/applications/{}/guilds/{}/commands",
+                self.api_url, self.application_id, guild_id
+            ),
+            None => format!("{}/applications/{}/commands", self.api_url, self.application_id),
+        };
+        let resp = self
+            .client
+            .put(&url)
+            .header("Authorization", format!("Bot {}", self.bot_token))
+            .json(&json!([raid_command()]))
+            .send()
+            .await?;
+        if !resp.status().is_success() {
+            return Err(anyhow!(
+                "registering commands failed with {}: {}",
+                resp.status(),
+                resp.text().await.unwrap_or_default()
+            ));
+        }
+        Ok(())
+    }
+
+    /// Answers `interaction` with `reply` as an embed
+    pub async fn respond(&self, interaction: &Interaction, reply: &Reply) -> Result<()> {
+        self.callback(
+            interaction,
+            json!({
+                "type": CHANNEL_MESSAGE_WITH_SOURCE,
+                "data": { "embeds": [reply.embed()] },
+            }),
+        )
+        .await
+    }
+
+    /// Acknowledges `interaction` for commands that take longer than the 3 seconds Discord waits,
+    /// showing that the bot is thinking until `edit_response` gives the answer
+    pub async fn defer(&self, interaction: &Interaction) -> Result<()> {
+        self.callback(interaction, json!({ "type": DEFERRED_CHANNEL_MESSAGE_WITH_SOURCE })).await
+    }
+
+    /// Replaces the deferred answer to `interaction` with `reply`
+    pub async fn edit_response(&self, interaction: &Interaction, reply: &Reply) -> Result<()> {
+        let url = format!(
+            "{}/webhooks/{}/{}/messages/@original",
+            self.api_url, self.application_id, interaction.token
+        );
+        let resp = self
+            .client
+            .patch(&url)
+            .json(&json!({ "embeds": [reply.embed()] }))
+            .send()
+            .await?;
+        if !resp.status().is_success() {
+            return Err(anyhow!("editing interaction response failed with {}", resp.status()));
+        }
+        Ok(())
+    }
+
+    async fn callback(&self, interaction: &Interaction, body: Value) -> Result<()> {
+        let url = format!(
+            "{}/interactions/{}/{}/callback",
+            self.api_url, interaction.id, interaction.token
+        );
+        let resp = self.client.post(&url).json(&body).send().await?;
+        if !resp.status().is_success() {
+            return Err(anyhow!("interaction response failed with {}", resp.status()));
+        }
+        Ok(())
+    }
+}
+
+/// The `/raid` command definition. Option names match the `!watch` and `!sweep` keys.
+pub fn raid_command() -> Value {
+    json!({
+        "name": "raid",
+        "description": "Watch AniList for raids",
+        "options": [
+            {
+                "type": SUB_COMMAND,
+                "name": "start",
+                "description": "Start a task",
+                "options": [
+                    {
+                        "type": STRING,
+                        "name": "target",
+                        "description": "What to look through",
+                        "required": true,
+                        "choices": [
+                            { "name": "activities", "value": "activities" },
+                            { "name": "users", "value": "users" },
+                        ],
+                    },
+                    {
+                        "type": STRING,
+                        "name": "webhook",
+                        "description": "Saved webhook to post hits to",
+                        "required": true,
+                    },
+                    {
+                        "type": STRING,
+                        "name": "keywords",
+                        "description": "Comma separated keywords, optionally prefixed with word: or regex:",
+                    },
+                    { "type": INTEGER, "name": "depth", "description": "Pages to look through" },
+                    { "type": INTEGER, "name": "interval", "description": "Seconds between polls" },
+                    { "type": INTEGER, "name": "jitter", "description": "Up to this many extra seconds between polls" },
+                    { "type": BOOLEAN, "name": "replies", "description": "Also check activity replies" },
+                    { "type": BOOLEAN, "name": "normalize", "description": "Fold lookalike characters before matching" },
+                    {
+                        "type": STRING,
+                        "name": "user-keywords",
+                        "description": "Keywords checked against the users who posted activities",
+                    },
+                    { "type": STRING, "name": "media", "description": "Comma separated media IDs to check user lists for" },
+                    { "type": INTEGER, "name": "max-score", "description": "Flag list entries scored at or below this" },
+                    { "type": BOOLEAN, "name": "full-sweep", "description": "Look through every page of users on the first sweep" },
+                    { "type": INTEGER, "name": "report-score", "description": "Lowest score worth reporting" },
+                    { "type": INTEGER, "name": "escalate-score", "description": "Score at which a hit is escalated" },
+                ],
+            },
+            {
+                "type": SUB_COMMAND,
+                "name": "stop",
+                "description": "Stop a running task",
+                "options": [
+                    { "type": INTEGER, "name": "id", "description": "Task to stop" },
+                    { "type": BOOLEAN, "name": "all", "description": "Stop every running task" },
+                ],
+            },
+            {
+                "type": SUB_COMMAND,
+                "name": "status",
+                "description": "Show running tasks and their progress",
+            },
+        ],
+    })
+}
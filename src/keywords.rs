@@ -0,0 +1,145 @@
+use crate::normalize::{normalize, Prepared};
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::fmt;
+use std::ops::Range;
+
+/// How a keyword is matched against text. Every kind is case-insensitive.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum KeywordKind {
+    /// Appears anywhere in the text, even inside another word
+    Literal,
+    /// Appears as a whole word, so "the" does not match "there"
+    Word,
+    /// A regular expression
+    Regex,
+}
+
+/// A keyword as written in the job JSON: either a bare string (a literal) or
+/// an object such as `{"word": "the"}` or `{"regex": "(https?://\\S+\\s*){3,}", "weight": 3}`
+#[derive(Deserialize, Serialize)]
+#[serde(untagged)]
+enum KeywordSpec {
+    Literal(String),
+    Tagged(TaggedKeyword),
+}
+
+#[derive(Deserialize, Serialize)]
+struct TaggedKeyword {
+    #[serde(flatten)]
+    pattern: TaggedPattern,
+    #[serde(default = "default_weight", skip_serializing_if = "is_default_weight")]
+    weight: u32,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum TaggedPattern {
+    Literal(String),
+    Word(String),
+    Regex(String),
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+fn is_default_weight(weight: &u32) -> bool {
+    *weight == default_weight()
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(try_from = "KeywordSpec", into = "KeywordSpec")]
+pub struct Keyword {
+    pub kind: KeywordKind,
+    pub pattern: String,
+    /// Added to a hit's score when the keyword matches (1 by default)
+    pub weight: u32,
+    regex: Regex,
+    /// Matches text that has been passed through `normalize`
+    normalized: Regex,
+}
+
+impl Keyword {
+    pub fn new(kind: KeywordKind, pattern: &str) -> Result<Self, regex::Error> {
+        // Literal and word keywords are normalized too, so "h4x" still matches
+        // normalized text; regexes only ever search the original text
+        let normalized = match kind {
+            KeywordKind::Regex => pattern.to_string(),
+            _ => normalize(pattern),
+        };
+        Ok(Keyword {
+            kind,
+            pattern: pattern.to_string(),
+            weight: default_weight(),
+            regex: Self::build(kind, pattern)?,
+            normalized: Self::build(kind, &normalized)?,
+        })
+    }
+
+    fn build(kind: KeywordKind, pattern: &str) -> Result<Regex, regex::Error> {
+        let source = match kind {
+            KeywordKind::Literal => regex::escape(pattern),
+            KeywordKind::Word => format!(r"\b{}\b", regex::escape(pattern)),
+            KeywordKind::Regex => pattern.to_string(),
+        };
+        RegexBuilder::new(&source).case_insensitive(true).build()
+    }
+
+    /// The byte range of `text.original` first matched by the keyword.
+    /// Regexes always search the original text, since folding would stop classes like `\d` matching.
+    pub fn find(&self, text: &Prepared) -> Option<Range<usize>> {
+        match &text.normalized {
+            Some(normalized) if self.kind != KeywordKind::Regex => {
+                let found = self.normalized.find(normalized)?;
+                Some(text.original_range(found.range()))
+            }
+            _ => self.regex.find(text.original).map(|found| found.range()),
+        }
+    }
+}
+
+impl TryFrom<KeywordSpec> for Keyword {
+    type Error = regex::Error;
+
+    fn try_from(spec: KeywordSpec) -> Result<Self, Self::Error> {
+        let tagged = match spec {
+            KeywordSpec::Literal(pattern) => return Keyword::new(KeywordKind::Literal, &pattern),
+            KeywordSpec::Tagged(tagged) => tagged,
+        };
+        let mut keyword = match tagged.pattern {
+            TaggedPattern::Literal(pattern) => Keyword::new(KeywordKind::Literal, &pattern)?,
+            TaggedPattern::Word(pattern) => Keyword::new(KeywordKind::Word, &pattern)?,
+            TaggedPattern::Regex(pattern) => Keyword::new(KeywordKind::Regex, &pattern)?,
+        };
+        keyword.weight = tagged.weight;
+        Ok(keyword)
+    }
+}
+
+impl From<Keyword> for KeywordSpec {
+    fn from(keyword: Keyword) -> Self {
+        let pattern = match keyword.kind {
+            KeywordKind::Literal if is_default_weight(&keyword.weight) => return KeywordSpec::Literal(keyword.pattern),
+            KeywordKind::Literal => TaggedPattern::Literal(keyword.pattern),
+            KeywordKind::Word => TaggedPattern::Word(keyword.pattern),
+            KeywordKind::Regex => TaggedPattern::Regex(keyword.pattern),
+        };
+        KeywordSpec::Tagged(TaggedKeyword {
+            pattern,
+            weight: keyword.weight,
+        })
+    }
+}
+
+impl fmt::Display for Keyword {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            KeywordKind::Literal => write!(f, "keyword \"{}\"", self.pattern),
+            KeywordKind::Word => write!(f, "word \"{}\"", self.pattern),
+            KeywordKind::Regex => write!(f, "regex /{}/", self.pattern),
+        }
+    }
+}
@@ -0,0 +1,16 @@
+pub mod anilist;
+pub mod auth;
+pub mod commands;
+pub mod config;
+pub mod resources;
+pub mod store;
+pub mod embeds;
+pub mod interactions;
+pub mod keywords;
+pub mod matches;
+pub mod normalize;
+pub mod profiles;
+pub mod ratelimit;
+pub mod tasks;
+pub mod webhooks;
+pub mod util;
@@ -1,144 +1,172 @@
 use anyhow::anyhow;
 use anyhow::Result;
+use serde_json::Value;
+use std::sync::Arc;
+use tokio::sync::mpsc::{self, UnboundedReceiver};
 use discord::{
-    model::{ChannelId, Event, Message, ReadyEvent},
+    model::{Channel, ChannelId, Event, Message, PossibleServer, ReadyEvent, ServerId, UserId},
     Connection, Discord,
 };
-use std::collections::BTreeSet;
-use once_cell::sync::Lazy;
-use std::sync::Mutex;
 
-pub mod anilist;
-pub mod resources;
-pub mod embeds;
-pub mod webhooks;
-pub mod util;
-
-use webhooks::*;
+use al_raid_bot::{
+    auth::{self, Denial, Origin},
+    commands::{self, Reply, StopTarget, ERROR_COLOR},
+    config::Config,
+    embeds::{truncate, MAX_DESCRIPTION_LEN},
+    interactions::{Interaction, InteractionsClient, RaidCommand},
+    profiles::{Webhook, WebhookProfiles},
+    tasks::{Notice, TaskRegistry},
+    util,
+    webhooks::*,
+};
 
-static CANCEL: Lazy<Mutex<bool>> = Lazy::new(|| Mutex::new(false));
+/// Longest pause between gateway reconnect attempts
+const MAX_RECONNECT_DELAY: u64 = 300;
+/// Consecutive receive errors tolerated before reconnecting from scratch
+const MAX_RECV_ERRORS: u32 = 5;
 
 #[tokio::main]
 async fn main() -> Result<()> {
     dotenv::dotenv().unwrap();
     let token = dotenv::var("BOT_TOKEN").expect("discord token not provided");
+    let config = Config::from_env();
+    let profiles = WebhookProfiles::load(&config.data_dir).expect("could not load webhook profiles");
     let discord = Discord::from_bot_token(&token).expect("could not initialize bot");
+    let (notices, notice_receiver) = mpsc::unbounded_channel();
+    let notice_discord = Discord::from_bot_token(&token).expect("could not initialize bot");
+    tokio::spawn(post_notices(notice_discord, notice_receiver));
     let (connection, ready_event) = discord.connect().expect("discord connection failed");
+    println!("connected to gateway, session {}", ready_event.session_id);
+    if !config.allowlist.restricts_users() {
+        println!("warning: no ALLOWED_USER_IDS or ALLOWED_ROLE_IDS set, anyone can run commands");
+    }
+    let interactions = match &config.application_id {
+        Some(application_id) => {
+            let client = InteractionsClient::new(&config.discord_api_url, application_id, &token);
+            match client.register_commands(config.command_guild_id.as_deref()).await {
+                Ok(()) => println!("registered slash commands"),
+                Err(err) => println!("could not register slash commands: {:?}", err),
+            }
+            Some(client)
+        }
+        None => {
+            println!("no APPLICATION_ID set, slash commands are disabled");
+            None
+        }
+    };
     Box::leak(Box::new(RaidBot {
         discord,
         connection,
-        _ready_event: ready_event,
-        job: None,
-        join_handle: None,
-        // page: 1,
+        ready_event,
+        tasks: TaskRegistry::new(&config, profiles.clone(), notices),
+        profiles,
+        interactions,
+        config,
+        reconnects: 0,
     })).run().await
 }
 
 struct RaidBot {
     discord: Discord,
     connection: Connection,
-    _ready_event: ReadyEvent,
-    job: Option<WebhookJob>,
-    join_handle: Option<tokio::task::JoinHandle<()>>
-    // page: i32,
+    /// Lists the servers the bot is in, where the roles of users who DM it are looked up
+    ready_event: ReadyEvent,
+    tasks: TaskRegistry,
+    profiles: WebhookProfiles,
+    /// Set when slash commands are enabled
+    interactions: Option<InteractionsClient>,
+    config: Config,
+    /// How many times the gateway connection has been re-established
+    reconnects: u32,
+}
+
+/// Who ran a command and where, whether by message or slash command
+struct Invoker {
+    user_id: u64,
+    /// `name#discriminator`, for the audit log
+    tag: String,
+    channel_id: u64,
+    /// Sent in a DM, which has no channel to check against the allowlist
+    private: bool,
 }
 
 impl RaidBot {
     async fn run(&'static mut self) -> Result<()> {
+        self.resume_tasks();
+        let mut recv_errors = 0;
         loop {
             match self.connection.recv_event() {
                 Ok(Event::MessageCreate(message)) => {
-                    match self.handle_message(message).await { //.await
-                        Ok(_) => {
-                            if let Some(_) = self.join_handle {
-                                continue;
-                            }
-                            match &mut self.job {
-                                Some(WebhookJob::Activity(job)) => {
-                                    let mut job = job.clone();
-                                    self.join_handle = Some(tokio::task::spawn(async move {
-                                        let mut cancel = {
-                                            *CANCEL.lock().unwrap()
-                                        };
-                                        while !cancel {
-                                            util::wait(10);
-                                            match job.job.find_activities(1) { //.await
-                                                Ok(activities) => {
-                                                    for (activity, matches) in activities {
-                                                        if let Err(err) = job.send_embed_activity(activity, matches) { //.await
-                                                            println!("could not send user embed: {:?}", err);
-                                                        }
-                                                    }
-                                                },
-                                                Err(err) => {
-                                                    println!("err in find activities: {:?}", err);
-                                                }
-                                            }
-                                            cancel = {
-                                                *CANCEL.lock().unwrap()
-                                            };
-                                        }
-                                    }));
-                                }
-                                Some(WebhookJob::User(job)) => {
-                                    // Look through `depth` pages of users
-                                    let mut job = job.clone();
-                                    self.join_handle = Some(tokio::task::spawn(async move {
-                                        let mut cancel = {
-                                            *CANCEL.lock().unwrap()
-                                        };
-                                        loop {
-                                            let depth = job.job.depth + 1;
-                                            for page in 1..depth {
-                                                println!("page #: {}", page);
-                                                match job.job.find_users(page) { //.await
-                                                    Ok(users) => {
-                                                        for (user, matches) in users {
-                                                            if let Err(err) = job.send_embed_user(user, matches) { //.await
-                                                                println!("could not send user embed: {:?}", err);
-                                                            }
-                                                        }
-                                                    },
-                                                    Err(err) => {
-                                                        println!("err in find users: {:?}", err);
-                                                    }
-                                                }
-                                                cancel = {
-                                                    *CANCEL.lock().unwrap()
-                                                };
-                                                if cancel {
-                                                    break;
-                                                }
-                                                // util::wait(2);
-                                            }
-                                            if cancel {
-                                                break;
-                                            }
-                                        }
-                                    }));
-                                }
-                                None => {}
+                    recv_errors = 0;
+                    let channel_id = message.channel_id;
+                    if let Err(err) = self.handle_message(message).await {
+                        println!("message recv err: {:?}", err);
+                        let reply = Reply::error("Command failed", format!("{:#}", err));
+                        if let Err(err) = self.send_reply(channel_id, &reply) {
+                            println!("could not report command error: {:?}", err);
+                        }
+                    }
+                }
+                Ok(Event::Unknown(name, object)) if name == "INTERACTION_CREATE" => {
+                    recv_errors = 0;
+                    let interaction = serde_json::from_value(Value::Object(object.into_iter().collect()));
+                    match interaction {
+                        Ok(interaction) => {
+                            if let Err(err) = self.handle_interaction(interaction).await {
+                                println!("interaction err: {:?}", err);
                             }
-                        },
-                        Err(err) => {
-                            println!("message recv err: {:?}", err);
                         }
+                        Err(err) => println!("could not read interaction: {:?}", err),
                     }
                 }
-                Ok(_) => {}
+                Ok(_) => {
+                    recv_errors = 0;
+                }
                 Err(discord::Error::Closed(code, body)) => {
+                    // The connection already tries to resume the session on its own,
+                    // so a close means the session is gone and a new one is needed
                     println!("Gateway closed. code {:?}: {}", code, body);
-                    break;
+                    self.reconnect().await;
+                    recv_errors = 0;
                 }
                 Err(err) => {
                     println!("Error receiving event: {:?}", err);
+                    recv_errors += 1;
+                    if recv_errors >= MAX_RECV_ERRORS {
+                        self.reconnect().await;
+                        recv_errors = 0;
+                    }
                 }
             }
         }
+    }
 
-        // self.discord.send_embed()
-
-        Ok(())
+    /// Opens a new gateway session, backing off exponentially between failed attempts.
+    /// Spawned tasks are independent of the gateway and keep running meanwhile.
+    async fn reconnect(&mut self) {
+        let mut delay = 1;
+        loop {
+            self.reconnects += 1;
+            println!("reconnecting to gateway (reconnect #{})...", self.reconnects);
+            match self.discord.connect() {
+                Ok((connection, ready_event)) => {
+                    println!(
+                        "reconnected to gateway, session {} (reconnect #{})",
+                        ready_event.session_id, self.reconnects
+                    );
+                    let old = std::mem::replace(&mut self.connection, connection);
+                    let _ = old.shutdown();
+                    self.ready_event = ready_event;
+                    self.resume_tasks();
+                    return;
+                }
+                Err(err) => {
+                    println!("reconnect #{} failed: {:?}", self.reconnects, err);
+                }
+            }
+            util::wait(delay).await;
+            delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+        }
     }
 
     async fn handle_message(&mut self, message: Message) -> Result<()> {
@@ -146,50 +174,274 @@ impl RaidBot {
             let mut cmd_iter = message.content.splitn(2, ' ');
             let cmd = cmd_iter
                 .next()
-                .ok_or(anyhow!("no command present in iterator"))?;
-
-            if cmd.starts_with("!start-task") {
-                let body = cmd_iter
-                    .next()
-                    .ok_or(anyhow!("no body accompanying command"))?;
-                let mut job = serde_json::from_str::<WebhookJob>(body)?;
-                match &mut job {
-                    WebhookJob::User(job) => {
-                        job.job.found_user_ids = Some(BTreeSet::new());
-                    },
-                    WebhookJob::Activity(job) => {
-                        job.job.found_activity_ids = Some(BTreeSet::new());
-                    }
+                .ok_or_else(|| anyhow!("no command present in iterator"))?;
+            // Other bots' commands and chat that happens to start with `!` are none of our business
+            if !commands::is_command(cmd) {
+                return Ok(());
+            }
+
+            let channel = match self.discord.get_channel(message.channel_id) {
+                Ok(channel) => Some(channel),
+                Err(err) => {
+                    println!("could not look up channel {}: {:?}", message.channel_id.0, err);
+                    None
+                }
+            };
+            let invoker = Invoker {
+                user_id: message.author.id.0,
+                tag: format!("{}#{}", message.author.name, message.author.discriminator),
+                channel_id: message.channel_id.0,
+                private: matches!(channel, Some(Channel::Private(_))),
+            };
+            let roles = || match &channel {
+                Some(Channel::Public(channel)) => self.member_roles(channel.server_id, message.author.id),
+                Some(Channel::Private(_)) => self.roles_in_servers(message.author.id),
+                _ => vec![],
+            };
+            // `!webhook add` is the one command that has to be sent by DM
+            let audited = match (cmd, cmd_iter.clone().next()) {
+                ("!webhook", Some(args)) if args.split_whitespace().next() == Some("add") => "!webhook add",
+                _ => cmd,
+            };
+            // A webhook token posted in a channel comes down whatever the verdict
+            let token_deleted = audited == "!webhook add"
+                && !invoker.private
+                && self.discord.delete_message(message.channel_id, message.id).is_ok();
+            if !self.authorize(&invoker, audited, roles) {
+                return Ok(());
+            }
+
+            let reply = if cmd.starts_with("!start-task") {
+                let body = cmd_iter.next().unwrap_or_default();
+                match serde_json::from_str::<WebhookJob>(body) {
+                    Ok(job) => self.start_task(invoker.channel_id, job),
+                    Err(err) => Reply::error(
+                        "Invalid job JSON",
+                        truncate(&commands::describe_json_error(body, &err), MAX_DESCRIPTION_LEN),
+                    ),
                 }
-                if let Some(handle) = &mut self.join_handle {
-                    println!("cancelling task to replace it...");
-                    handle.abort();
-                    *CANCEL.lock().unwrap() = true;
-                    println!("task cancelled? {:?}", handle.await);
+            } else if cmd == "!watch" || cmd == "!sweep" {
+                let args = cmd_iter.next().unwrap_or_default();
+                let job = if cmd == "!watch" {
+                    commands::parse_watch(args)
+                } else {
+                    commands::parse_sweep(args)
+                };
+                match job {
+                    Ok(job) => self.start_task(invoker.channel_id, job),
+                    Err(err) => Reply::error("Could not read command", err.to_string()),
                 }
-                self.join_handle = None;
-                self.job = Some(job);
-                *CANCEL.lock().unwrap() = false;
-                self.handle_message_response(
-                    message.channel_id,
-                    "Started task successfully. Any previously running task was cancelled.",
-                )?;
             } else if cmd.starts_with("!stop-task") {
-                self.job = None;
-                if let Some(handle) = &mut self.join_handle {
-                    println!("cancelling task...");
-                    handle.abort();
-                    *CANCEL.lock().unwrap() = true;
-                    println!("task cancelled? {:?}", handle.await);
+                let target = cmd_iter.next().and_then(StopTarget::parse);
+                self.stop_tasks(target, "Usage: `!stop-task <id>` or `!stop-task all`.").await
+            } else if cmd == "!status" {
+                self.status()
+            } else if cmd == "!webhook" {
+                let args = cmd_iter.next().unwrap_or_default();
+                return self.handle_webhook_command(&message, args, invoker.private, token_deleted);
+            } else {
+                return Ok(());
+            };
+            self.send_reply(message.channel_id, &reply)?;
+        }
+        // Anything else is ordinary chat
+        Ok(())
+    }
+
+    /// Runs a `/raid` slash command and answers it through the interactions API
+    async fn handle_interaction(&mut self, interaction: Interaction) -> Result<()> {
+        let client = match &self.interactions {
+            Some(client) if interaction.is_raid_command() => client.clone(),
+            _ => return Ok(()),
+        };
+        let user = interaction.user().ok_or_else(|| anyhow!("interaction has no user"))?;
+        let invoker = Invoker {
+            user_id: user.id.parse()?,
+            tag: format!("{}#{}", user.username, user.discriminator),
+            channel_id: interaction.channel_id().unwrap_or_default(),
+            private: interaction.guild_id.is_none(),
+        };
+
+        // Unlike messages, an unanswered interaction shows up as failed, so denials get a reply
+        let reply = if !self.authorize(&invoker, &interaction.command_name(), || interaction.role_ids()) {
+            Reply::error("Not allowed", "You can't run this command here.")
+        } else {
+            match interaction.command() {
+                Ok(RaidCommand::Start(job)) => self.start_task(invoker.channel_id, *job),
+                Ok(RaidCommand::Stop(target)) => {
+                    // Busy tasks can take longer to stop than Discord waits for an answer
+                    client.defer(&interaction).await?;
+                    let reply = self.stop_tasks(target, "Give `/raid stop` a task `id`, or set `all`.").await;
+                    return client.edit_response(&interaction, &reply).await;
+                }
+                Ok(RaidCommand::Status) => self.status(),
+                Err(err) => Reply::error("Could not read command", err.to_string()),
+            }
+        };
+        client.respond(&interaction, &reply).await
+    }
+
+    /// Starts `job`, replying with the ID it was given
+    fn start_task(&mut self, channel_id: u64, mut job: WebhookJob) -> Reply {
+        job.reset();
+        match self.tasks.start(job, channel_id) {
+            Ok(id) => Reply::Info(format!(
+                "Started task {} successfully. Stop it with `!stop-task {}`.",
+                id, id
+            )),
+            Err(err) => Reply::error("Could not start task", format!("{:#}", err)),
+        }
+    }
+
+    /// Stops one or every task. Without a target, replies with `usage` and the running task IDs.
+    async fn stop_tasks(&mut self, target: Option<StopTarget>, usage: &str) -> Reply {
+        match target {
+            Some(StopTarget::All) => {
+                let count = self.tasks.stop_all().await;
+                Reply::Info(format!("Stopped {} task(s).", count))
+            }
+            Some(StopTarget::One(id)) => Reply::Info(if self.tasks.stop(id).await {
+                format!("Stopped task {}.", id)
+            } else {
+                format!("No task with ID {} is running.", id)
+            }),
+            None => {
+                let running = self
+                    .tasks
+                    .iter()
+                    .map(|(id, _)| id.to_string())
+                    .collect::<Vec<_>>();
+                let running = if running.is_empty() {
+                    "none".to_string()
+                } else {
+                    running.join(", ")
+                };
+                Reply::Info(format!("{} Running tasks: {}", usage, running))
+            }
+        }
+    }
+
+    fn status(&self) -> Reply {
+        Reply::Info(truncate(&self.tasks.status(), MAX_DESCRIPTION_LEN))
+    }
+
+    /// Manages named webhook profiles. Adding one is only accepted in DMs,
+    /// since the command carries the webhook token.
+    /// `token_deleted` says whether a `!webhook add` sent outside a DM has already been taken down
+    fn handle_webhook_command(&mut self, message: &Message, args: &str, private: bool, token_deleted: bool) -> Result<()> {
+        let usage = "Usage: `!webhook add <name> <webhook url>` (DMs only), `!webhook remove <name>` or `!webhook list`";
+        let mut args = args.split_whitespace();
+        let description = match (args.next(), args.next()) {
+            (Some("add"), Some(_)) if !private => {
+                format!(
+                    "`!webhook add` only works in DMs. {} Consider regenerating the webhook's token.",
+                    if token_deleted {
+                        "Your message was deleted."
+                    } else {
+                        "Your message could not be deleted, please remove it."
+                    }
+                )
+            }
+            (Some("add"), Some(name)) => {
+                let rest = args.collect::<Vec<_>>().join(" ");
+                match Webhook::parse(&rest) {
+                    Some(webhook) => {
+                        self.profiles.insert(name, webhook)?;
+                        format!("Saved webhook `{}`. Use `\"webhook\": \"{}\"` in a job to post to it.", name, name)
+                    }
+                    None => usage.to_string(),
+                }
+            }
+            (Some("remove"), Some(name)) => {
+                if self.profiles.remove(name)? {
+                    format!("Removed webhook `{}`.", name)
+                } else {
+                    format!("No webhook named `{}`.", name)
+                }
+            }
+            (Some("list"), None) => {
+                let names = self.profiles.names();
+                if names.is_empty() {
+                    "No webhooks saved.".to_string()
+                } else {
+                    format!("Saved webhooks: {}", names.join(", "))
                 }
-                self.join_handle = None;
-                // self.page = 1;
-                self.handle_message_response(message.channel_id, "Stopping current task.")?;
             }
+            _ => usage.to_string(),
+        };
+        self.handle_message_response(message.channel_id, &description)
+    }
 
-            Ok(())
+    /// Checks the invoker and channel against the allowlist, audit logging the outcome.
+    /// `role_ids` is only called when the allowlist depends on roles.
+    fn authorize(&self, invoker: &Invoker, cmd: &str, role_ids: impl FnOnce() -> Vec<u64>) -> bool {
+        let allowlist = &self.config.allowlist;
+        let role_ids = if allowlist.needs_roles(invoker.user_id) {
+            role_ids()
         } else {
-            Err(anyhow!("no command detected"))
+            vec![]
+        };
+        let origin = if invoker.private {
+            Origin::Direct {
+                dm_only: cmd == "!webhook add",
+            }
+        } else {
+            Origin::Channel(invoker.channel_id)
+        };
+        let outcome = allowlist.check(invoker.user_id, &role_ids, origin);
+        let verdict = match outcome {
+            Ok(()) => "allowed",
+            Err(Denial::Channel) => "rejected (channel not allowed)",
+            Err(Denial::DirectMessage) => "rejected (DMs not allowed)",
+            Err(Denial::User) => "rejected (user not allowed)",
+        };
+        auth::audit(
+            &self.config.data_dir,
+            &format!(
+                "{} {} by {} ({}) in channel {}",
+                cmd, verdict, invoker.tag, invoker.user_id, invoker.channel_id
+            ),
+        );
+        outcome.is_ok()
+    }
+
+    /// Role IDs `user_id` holds in the given server
+    fn member_roles(&self, server_id: ServerId, user_id: UserId) -> Vec<u64> {
+        match self.discord.get_member(server_id, user_id) {
+            Ok(member) => member.roles.iter().map(|role| role.0).collect(),
+            Err(err) => {
+                println!("could not look up roles of {} in {}: {:?}", user_id.0, server_id.0, err);
+                vec![]
+            }
+        }
+    }
+
+    /// Role IDs `user_id` holds in any server the bot is in, since a DM carries no roles
+    fn roles_in_servers(&self, user_id: UserId) -> Vec<u64> {
+        self.ready_event
+            .servers
+            .iter()
+            .map(|server| match server {
+                PossibleServer::Online(server) => server.id,
+                PossibleServer::Offline(id) => *id,
+            })
+            .flat_map(|server_id| self.member_roles(server_id, user_id))
+            .collect()
+    }
+
+    /// Restarts the tasks that were running before the bot went down
+    fn resume_tasks(&mut self) {
+        match self.tasks.resume() {
+            Ok(resumed) => {
+                for (id, channel_id) in resumed {
+                    println!("resumed task {}", id);
+                    let notice = format!("Resumed task {} after a restart. Stop it with `!stop-task {}`.", id, id);
+                    if let Err(err) = self.handle_message_response(ChannelId(channel_id), &notice) {
+                        println!("could not post resume notice for task {}: {:?}", id, err);
+                    }
+                }
+            }
+            Err(err) => println!("could not resume tasks: {:?}", err),
         }
     }
 
@@ -199,12 +451,47 @@ impl RaidBot {
         Ok(())
     }
 
+    /// Sends a command's reply, errors as a red embed
+    fn send_reply(&mut self, channel_id: ChannelId, reply: &Reply) -> Result<()> {
+        match reply {
+            Reply::Info(description) => self.handle_message_response(channel_id, description),
+            Reply::Error { title, description } => {
+                self.discord.send_embed(channel_id, "", |embed| {
+                    embed.title(title).description(description).color(ERROR_COLOR)
+                })?;
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Posts what running tasks report, such as a webhook that stopped working,
+/// to the channels they were started from
+async fn post_notices(discord: Discord, mut notices: UnboundedReceiver<Notice>) {
+    let discord = Arc::new(discord);
+    while let Some(notice) = notices.recv().await {
+        let task = notice.task;
+        let discord = discord.clone();
+        // discord-rs blocks on its requests, which would stall the runtime's worker
+        let sent = tokio::task::spawn_blocking(move || {
+            let title = format!("Task {} needs attention", notice.task);
+            discord.send_embed(ChannelId(notice.channel_id), "", |embed| {
+                embed.title(&title).description(&notice.message).color(ERROR_COLOR)
+            })
+        })
+        .await;
+        match sent {
+            Ok(Ok(_)) => {}
+            Ok(Err(err)) => println!("could not post notice for task {}: {:?}", task, err),
+            Err(err) => println!("posting notice for task {} failed: {:?}", task, err),
+        }
+    }
 }
 
 /*
 Example commands
 
-!start-raid {
+!start-task {
     "User": {
         "channelId": "id",
         "token": "tok",
@@ -217,7 +504,7 @@ Example commands
     }
 }
 
-!start-raid {
+!start-task {
     "Activity": {
         "channelId": "id",
         "token": "tok",
@@ -0,0 +1,202 @@
+use crate::embeds::{snippet, EmbedField, Severity};
+use crate::keywords::{Keyword, KeywordKind};
+use serde::Serialize;
+use std::fmt;
+use std::ops::Range;
+
+/// One reason something was flagged, and how much it adds to the hit's score
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Match {
+    /// Names the rule the same way wherever it is used, so hits can be grouped by it, see `Rule::id`
+    pub rule_id: String,
+    pub rule: Rule,
+    pub target: Target,
+    /// Where in the text the rule matched, for rules that look at text
+    pub span: Option<Span>,
+    pub ids: EntityIds,
+    pub weight: u32,
+}
+
+pub type Matches = Vec<Match>;
+
+/// The rule that produced a match
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Rule {
+    Keyword(Keyword),
+    /// A list entry scored at or below this
+    LowListScore(i32),
+    /// The user's list is private, so its scores couldn't be checked
+    PrivateList,
+}
+
+impl Rule {
+    /// A stable key for the rule, such as `word:the`, `regex:n\d+`, `low-list-score:30` or `private-list`
+    pub fn id(&self) -> String {
+        match self {
+            Rule::Keyword(keyword) => {
+                let kind = match keyword.kind {
+                    KeywordKind::Literal => "literal",
+                    KeywordKind::Word => "word",
+                    KeywordKind::Regex => "regex",
+                };
+                format!("{}:{}", kind, keyword.pattern)
+            }
+            Rule::LowListScore(max_score) => format!("low-list-score:{}", max_score),
+            Rule::PrivateList => "private-list".to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Rule::Keyword(keyword) => write!(f, "{}", keyword),
+            Rule::LowListScore(max_score) => write!(f, "list score at or below {}", max_score),
+            Rule::PrivateList => write!(f, "private list"),
+        }
+    }
+}
+
+/// What a rule was checked against
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Target {
+    Activity,
+    Reply,
+    Username,
+    Bio,
+    ListEntry,
+}
+
+impl Target {
+    /// The name of the target's field on a hit
+    pub fn name(self) -> &'static str {
+        match self {
+            Target::Activity => "Activity",
+            Target::Reply => "Activity Reply",
+            Target::Username => "Username",
+            Target::Bio => "Bio",
+            Target::ListEntry => "List Entry Score",
+        }
+    }
+}
+
+/// The part of some text a rule matched
+#[derive(Debug, Clone, Serialize)]
+pub struct Span {
+    /// The text that was searched, as written even if the job normalizes
+    pub text: String,
+    /// Byte range of the match within `text`
+    pub range: Range<usize>,
+}
+
+impl Span {
+    pub fn matched(&self) -> &str {
+        &self.text[self.range.clone()]
+    }
+}
+
+/// The AniList entities a match concerns
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EntityIds {
+    pub user_id: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub activity_id: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reply_id: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub media_id: Option<i32>,
+}
+
+impl Match {
+    pub fn new(rule: Rule, target: Target, span: Option<Span>, ids: EntityIds, weight: u32) -> Self {
+        Match {
+            rule_id: rule.id(),
+            rule,
+            target,
+            span,
+            ids,
+            weight,
+        }
+    }
+
+    /// A match for `keyword` at `range` of `text`
+    pub fn keyword(keyword: &Keyword, target: Target, text: &str, range: Range<usize>, ids: EntityIds) -> Self {
+        let span = Span {
+            text: text.to_string(),
+            range,
+        };
+        Match::new(Rule::Keyword(keyword.clone()), target, Some(span), ids, keyword.weight)
+    }
+
+    /// The match as shown on a hit: where it was found, what matched and the text around it
+    pub fn field(&self) -> EmbedField {
+        let found = self.span.as_ref().map(|span| snippet(&span.text, span.range.clone()));
+        let value = match (&self.rule, self.target, found) {
+            (Rule::LowListScore(_), _, _) => {
+                format!("Media ID poorly scored: {}", self.ids.media_id.unwrap_or_default())
+            }
+            (Rule::PrivateList, _, _) => "List is private, scores could not be checked".to_string(),
+            (rule, Target::Username, Some(found)) => format!("Username matched {}: {}", rule, found),
+            (rule, Target::Bio, Some(found)) => format!("Bio matched {}: {}", rule, found),
+            (rule, _, Some(found)) => format!("Matched {}: {}", rule, found),
+            (rule, _, None) => format!("Matched {}", rule),
+        };
+        EmbedField::from(self.target.name().to_string(), value)
+    }
+}
+
+/// The total weight of a hit's matches against the thresholds of the job that found it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Score {
+    pub total: u32,
+    /// Lowest total that gets reported
+    pub report: u32,
+    /// Lowest total that gets escalated, if the job escalates at all
+    pub escalate: Option<u32>,
+}
+
+impl Score {
+    /// Scores `matches`, reporting at 1 unless `report` says otherwise
+    pub fn of(matches: &[Match], report: Option<u32>, escalate: Option<u32>) -> Self {
+        Score {
+            total: matches.iter().map(|m| m.weight).fold(0u32, u32::saturating_add),
+            report: report.unwrap_or(1),
+            escalate,
+        }
+    }
+
+    pub fn reported(&self) -> bool {
+        self.total >= self.report
+    }
+
+    pub fn escalated(&self) -> bool {
+        matches!(self.escalate, Some(escalate) if self.total >= escalate)
+    }
+
+    /// High once escalated, medium above the report threshold and low right at it
+    pub fn severity(&self) -> Severity {
+        if self.escalated() {
+            Severity::High
+        } else if self.total > self.report {
+            Severity::Medium
+        } else {
+            Severity::Low
+        }
+    }
+
+    /// The score and the matches that add up to it, as shown on a hit
+    pub fn field(&self, matches: &[Match]) -> EmbedField {
+        let mut lines = vec![match self.escalate {
+            Some(escalate) => format!("**{}** (reported at {}, escalated at {})", self.total, self.report, escalate),
+            None => format!("**{}** (reported at {})", self.total, self.report),
+        }];
+        for m in matches.iter().filter(|m| m.weight > 0) {
+            lines.push(format!("+{} {}: {}", m.weight, m.target.name(), m.rule));
+        }
+        EmbedField::from("Score".to_string(), lines.join("\n"))
+    }
+}
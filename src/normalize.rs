@@ -0,0 +1,117 @@
+use std::ops::Range;
+use unicode_normalization::{char::is_combining_mark, UnicodeNormalization};
+
+/// Folds text into a plain lowercase form before keyword matching, so that
+/// fullwidth letters, zero-width characters, accents, lookalike letters from
+/// other scripts and common leetspeak all match the ASCII keyword they imitate
+pub fn normalize(text: &str) -> String {
+    text.chars().flat_map(fold).collect()
+}
+
+/// Folds a single character, which may become none or several.
+/// Folding char by char gives the same text as folding it whole, since combining marks are dropped.
+fn fold(c: char) -> impl Iterator<Item = char> {
+    std::iter::once(c)
+        .nfkd()
+        .filter(|c| !is_combining_mark(*c) && !is_invisible(*c))
+        .flat_map(char::to_lowercase)
+        .map(fold_confusable)
+        .map(fold_leet)
+}
+
+/// Text ready for keyword matching: as written, and folded too if the job normalizes
+pub struct Prepared<'t> {
+    pub original: &'t str,
+    pub normalized: Option<String>,
+    /// For each byte of `normalized`, the bytes of the original character it was folded from
+    sources: Vec<Range<usize>>,
+}
+
+impl Prepared<'_> {
+    /// Maps a byte range of `normalized` back to the part of `original` it was folded from
+    pub fn original_range(&self, range: Range<usize>) -> Range<usize> {
+        let start = self.sources.get(range.start).map_or(self.original.len(), |source| source.start);
+        let end = match range.end.checked_sub(1).and_then(|last| self.sources.get(last)) {
+            Some(source) if range.end > range.start => source.end,
+            _ => start,
+        };
+        start..end
+    }
+}
+
+/// Normalizes `text` only when `enabled`
+pub fn prepare(text: &str, enabled: bool) -> Prepared<'_> {
+    let mut prepared = Prepared {
+        original: text,
+        normalized: None,
+        sources: vec![],
+    };
+    if enabled {
+        let mut normalized = String::with_capacity(text.len());
+        for (start, c) in text.char_indices() {
+            for folded in fold(c) {
+                normalized.push(folded);
+                let len = prepared.sources.len() + folded.len_utf8();
+                prepared.sources.resize(len, start..start + c.len_utf8());
+            }
+        }
+        prepared.normalized = Some(normalized);
+    }
+    prepared
+}
+
+fn is_invisible(c: char) -> bool {
+    matches!(
+        c,
+        '\u{00AD}' | '\u{034F}' | '\u{180E}' | '\u{200B}'..='\u{200F}' | '\u{202A}'..='\u{202E}'
+            | '\u{2060}'..='\u{2064}' | '\u{FEFF}'
+    )
+}
+
+/// Maps lowercase Cyrillic and Greek letters to the Latin letters they look like
+fn fold_confusable(c: char) -> char {
+    match c {
+        'а' | 'α' => 'a',
+        'в' | 'β' => 'b',
+        'с' | 'ϲ' => 'c',
+        'ԁ' => 'd',
+        'е' | 'ё' | 'ε' => 'e',
+        'ɡ' => 'g',
+        'һ' | 'н' | 'η' => 'h',
+        'і' | 'ї' | 'ι' => 'i',
+        'ј' => 'j',
+        'к' | 'κ' => 'k',
+        'ӏ' => 'l',
+        'м' => 'm',
+        'п' => 'n',
+        'о' | 'ο' | 'σ' => 'o',
+        'р' | 'ρ' => 'p',
+        'ԛ' => 'q',
+        'ѕ' => 's',
+        'т' | 'τ' => 't',
+        'υ' => 'u',
+        'ν' => 'v',
+        'ԝ' | 'ω' => 'w',
+        'х' | 'χ' => 'x',
+        'у' | 'γ' => 'y',
+        'ζ' => 'z',
+        _ => c,
+    }
+}
+
+/// Maps common leetspeak digits and symbols back to letters
+fn fold_leet(c: char) -> char {
+    match c {
+        '4' | '@' => 'a',
+        '8' => 'b',
+        '3' => 'e',
+        '6' => 'g',
+        '1' | '!' => 'i',
+        '|' => 'l',
+        '0' => 'o',
+        '5' | '$' => 's',
+        '7' => 't',
+        '2' => 'z',
+        _ => c,
+    }
+}
@@ -0,0 +1,89 @@
+use crate::store::{load_json, save_json};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+/// Credentials of a Discord webhook
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Webhook {
+    pub id: String,
+    pub token: String,
+}
+
+impl Webhook {
+    /// Accepts either a full webhook URL or `<id> <token>`
+    pub fn parse(value: &str) -> Option<Webhook> {
+        let mut parts: Vec<&str> = value
+            .trim()
+            .trim_end_matches('/')
+            .rsplit(|c: char| c == '/' || c.is_whitespace())
+            .filter(|part| !part.is_empty())
+            .take(2)
+            .collect();
+        if parts.len() != 2 {
+            return None;
+        }
+        let id = parts.pop()?;
+        let token = parts.pop()?;
+        if id.parse::<u64>().is_err() {
+            return None;
+        }
+        Some(Webhook {
+            id: id.to_string(),
+            token: token.to_string(),
+        })
+    }
+}
+
+/// Named webhook destinations, kept in `webhooks.json` so jobs can refer to them
+/// by name instead of carrying tokens through chat
+#[derive(Clone, Default)]
+pub struct WebhookProfiles {
+    path: Option<PathBuf>,
+    profiles: Arc<RwLock<BTreeMap<String, Webhook>>>,
+}
+
+impl WebhookProfiles {
+    pub fn load(data_dir: &Path) -> Result<Self> {
+        let path = data_dir.join("webhooks.json");
+        let profiles = load_json(&path)?.unwrap_or_default();
+        Ok(WebhookProfiles {
+            path: Some(path),
+            profiles: Arc::new(RwLock::new(profiles)),
+        })
+    }
+
+    pub fn get(&self, name: &str) -> Option<Webhook> {
+        self.profiles.read().unwrap().get(name).cloned()
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.profiles.read().unwrap().keys().cloned().collect()
+    }
+
+    /// Adds or replaces a profile and saves the file
+    pub fn insert(&self, name: &str, webhook: Webhook) -> Result<()> {
+        let mut profiles = self.profiles.write().unwrap();
+        profiles.insert(name.to_string(), webhook);
+        self.save(&profiles)
+    }
+
+    /// Returns false if there was no profile with that name
+    pub fn remove(&self, name: &str) -> Result<bool> {
+        let mut profiles = self.profiles.write().unwrap();
+        if profiles.remove(name).is_none() {
+            return Ok(false);
+        }
+        self.save(&profiles)?;
+        Ok(true)
+    }
+
+    fn save(&self, profiles: &BTreeMap<String, Webhook>) -> Result<()> {
+        match &self.path {
+            Some(path) => save_json(path, profiles),
+            None => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,105 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// A token bucket shared by every clone, refilled evenly over `window`
+#[derive(Clone)]
+pub struct RateLimiter {
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+struct Bucket {
+    /// Requests allowed per window
+    limit: u32,
+    window: Duration,
+    tokens: f64,
+    refilled_at: Instant,
+    /// What the server last said was left, if it said anything
+    reported_remaining: Option<u32>,
+}
+
+/// A snapshot of the limiter, for `!status`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimitUsage {
+    pub limit: u32,
+    pub available: u32,
+    pub reported_remaining: Option<u32>,
+}
+
+impl Bucket {
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        let rate = f64::from(self.limit) / self.window.as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(f64::from(self.limit));
+        self.refilled_at = now;
+    }
+
+    /// How long until the next whole token is available
+    fn next_token(&self) -> Duration {
+        let rate = f64::from(self.limit.max(1)) / self.window.as_secs_f64();
+        Duration::from_secs_f64((1.0 - self.tokens).max(0.0) / rate)
+    }
+}
+
+impl RateLimiter {
+    /// Starts with a full bucket of `limit` requests per `window`
+    pub fn new(limit: u32, window: Duration) -> Self {
+        RateLimiter {
+            bucket: Arc::new(Mutex::new(Bucket {
+                limit,
+                window,
+                tokens: f64::from(limit),
+                refilled_at: Instant::now(),
+                reported_remaining: None,
+            })),
+        }
+    }
+
+    /// Waits until a request may be sent, then uses up a token for it
+    pub async fn acquire(&self) {
+        loop {
+            let wait = {
+                let mut bucket = self.bucket.lock().unwrap();
+                bucket.refill();
+                if bucket.tokens >= 1.0 {
+                    bucket.tokens -= 1.0;
+                    return;
+                }
+                bucket.next_token()
+            };
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Syncs the bucket with the limit and remaining requests the server reported
+    pub fn update(&self, limit: Option<u32>, remaining: Option<u32>) {
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.refill();
+        if let Some(limit) = limit.filter(|limit| *limit > 0) {
+            bucket.limit = limit;
+            bucket.tokens = bucket.tokens.min(f64::from(limit));
+        }
+        if let Some(remaining) = remaining {
+            bucket.tokens = bucket.tokens.min(f64::from(remaining));
+            bucket.reported_remaining = Some(remaining);
+        }
+    }
+
+    /// Empties the bucket, for when the server throttled us anyway
+    pub fn drain(&self) {
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.refill();
+        bucket.tokens = 0.0;
+        bucket.reported_remaining = Some(0);
+    }
+
+    pub fn usage(&self) -> RateLimitUsage {
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.refill();
+        RateLimitUsage {
+            limit: bucket.limit,
+            available: bucket.tokens.floor() as u32,
+            reported_remaining: bucket.reported_remaining,
+        }
+    }
+}
@@ -0,0 +1,115 @@
+use anyhow::{anyhow, Result};
+use serde::{de::DeserializeOwned, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+/// Something a job has already reported
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SeenEntry {
+    Activity(i32),
+    Reply { id: i32, activity_id: i32 },
+    User(i32),
+}
+
+impl SeenEntry {
+    fn to_line(self) -> String {
+        match self {
+            SeenEntry::Activity(id) => format!("activity {}", id),
+            SeenEntry::Reply { id, activity_id } => format!("reply {} {}", id, activity_id),
+            SeenEntry::User(id) => format!("user {}", id),
+        }
+    }
+
+    fn from_line(line: &str) -> Result<Self> {
+        let mut parts = line.split_whitespace();
+        let kind = parts.next();
+        let mut id = || -> Result<i32> {
+            parts
+                .next()
+                .ok_or_else(|| anyhow!("missing id"))?
+                .parse::<i32>()
+                .map_err(|err| anyhow!("bad id: {}", err))
+        };
+        match kind {
+            Some("activity") => Ok(SeenEntry::Activity(id()?)),
+            Some("reply") => Ok(SeenEntry::Reply {
+                id: id()?,
+                activity_id: id()?,
+            }),
+            Some("user") => Ok(SeenEntry::User(id()?)),
+            _ => Err(anyhow!("unknown entry kind")),
+        }
+    }
+}
+
+/// The append-only file holding what has been reported for a `kind` of job to a webhook.
+/// Keying on the destination means a restarted or re-issued task skips old hits.
+pub fn seen_path(data_dir: &Path, kind: &str, webhook_id: &str) -> PathBuf {
+    let webhook_id: String = webhook_id
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '-' || *c == '_')
+        .collect();
+    data_dir.join("seen").join(format!("{}-{}.log", kind, webhook_id))
+}
+
+/// Reads every entry recorded so far, skipping lines that cannot be parsed
+pub fn load_seen(path: &Path) -> Result<Vec<SeenEntry>> {
+    let file = match fs::File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => return Err(err.into()),
+    };
+    let mut entries = vec![];
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        match SeenEntry::from_line(&line) {
+            Ok(entry) => entries.push(entry),
+            Err(err) => println!("skipping seen entry {:?} in {:?}: {}", line, path, err),
+        }
+    }
+    Ok(entries)
+}
+
+pub fn append_seen(path: &Path, entries: &[SeenEntry]) -> Result<()> {
+    if entries.is_empty() {
+        return Ok(());
+    }
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    let mut lines = String::new();
+    for entry in entries {
+        lines.push_str(&entry.to_line());
+        lines.push('\n');
+    }
+    file.write_all(lines.as_bytes())?;
+    Ok(())
+}
+
+/// Reads a JSON file, returning `None` if it does not exist yet
+pub fn load_json<T>(path: &Path) -> Result<Option<T>>
+where
+    T: DeserializeOwned,
+{
+    match fs::read_to_string(path) {
+        Ok(contents) => Ok(Some(serde_json::from_str(&contents)?)),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Writes a JSON file through a temporary file so a crash never leaves it half written
+pub fn save_json<T>(path: &Path, value: &T) -> Result<()>
+where
+    T: Serialize,
+{
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, serde_json::to_string_pretty(value)?)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
@@ -0,0 +1,379 @@
+use crate::anilist::AniListClient;
+use crate::config::Config;
+use crate::profiles::WebhookProfiles;
+use crate::store::{load_json, save_json};
+use crate::util;
+use crate::webhooks::*;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::{mpsc::UnboundedSender, Notify};
+use tokio::task::JoinHandle;
+
+pub type TaskId = u32;
+
+/// A job that has been spawned onto the runtime
+pub struct Task {
+    pub job: WebhookJob,
+    /// Channel the task was started from, where notices about it are posted
+    pub channel_id: u64,
+    cancel: Arc<Cancel>,
+    handle: JoinHandle<()>,
+}
+
+/// Tells a running task to stop, waking it if it is waiting for its next poll
+#[derive(Default)]
+struct Cancel {
+    cancelled: AtomicBool,
+    wake: Notify,
+}
+
+impl Cancel {
+    fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+        self.wake.notify_one();
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
+
+/// A task as written to `tasks.json`, so it can be resumed after a restart
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SavedTask {
+    channel_id: u64,
+    job: WebhookJob,
+}
+
+impl Task {
+    /// Describes the job and its progress for `!status`
+    fn status(&self, id: TaskId) -> Vec<String> {
+        let stats = self.job.stats().lock().unwrap().clone();
+        let started = stats
+            .started_at
+            .and_then(|started| started.duration_since(UNIX_EPOCH).ok())
+            .map(|since| format!("<t:{}:R>", since.as_secs()))
+            .unwrap_or_else(|| "not yet".to_string());
+        // Scoring nothing still gives the job's thresholds
+        let (kind, keywords, depth, score) = match &self.job {
+            WebhookJob::Activity(job) => {
+                ("activities", &job.job.keywords, job.job.depth.unwrap_or(1), job.job.score(&[]))
+            }
+            WebhookJob::User(job) => ("users", &job.job.keywords, job.job.depth, job.job.score(&[])),
+        };
+        let keywords = keywords
+            .iter()
+            .map(|keyword| match keyword.weight {
+                1 => keyword.to_string(),
+                weight => format!("{} (weight {})", keyword, weight),
+            })
+            .collect::<Vec<_>>();
+        let (interval, jitter) = self.job.interval();
+        let depth = format!("Depth: {} page(s), polled every {}s (+ up to {}s)", depth, interval, jitter);
+        let mut lines = vec![
+            format!("**Task {}**: watching {}, started {}", id, kind, started),
+            format!("Keywords: {}", if keywords.is_empty() { "none".to_string() } else { keywords.join(", ") }),
+            depth,
+            match score.escalate {
+                Some(escalate) => format!("Reports hits scoring {} or more, escalates at {}", score.report, escalate),
+                None => format!("Reports hits scoring {} or more", score.report),
+            },
+            format!(
+                "Scanned {} page(s), checked {} item(s), flagged {} ({} escalated)",
+                stats.pages_scanned, stats.items_checked, stats.items_flagged, stats.items_escalated
+            ),
+        ];
+        if stats.private_lists > 0 {
+            lines.push(format!("Private lists skipped: {}", stats.private_lists));
+        }
+        if let Some(err) = stats.last_error {
+            lines.push(format!("Last AniList error: {}", err));
+        }
+        if stats.pending_hits > 0 {
+            lines.push(format!("Waiting to deliver: {} hit(s)", stats.pending_hits));
+        }
+        if let Some(err) = stats.delivery_error {
+            lines.push(format!("Webhook delivery failing: {}", err));
+        }
+        if stats.stopped {
+            lines.push("Stopped polling because AniList rejected the query, stop and restart the task once fixed".to_string());
+        }
+        lines
+    }
+
+    /// Flag the task as cancelled and wait for it to wind down,
+    /// aborting it if it is still busy after `STOP_TIMEOUT`
+    async fn stop(self) {
+        self.cancel.cancel();
+        self.join(Instant::now() + STOP_TIMEOUT).await;
+    }
+
+    /// Waits for a cancelled task to finish, aborting it if it is still busy at `deadline`
+    async fn join(self, deadline: Instant) {
+        let mut handle = self.handle;
+        let deadline = tokio::time::Instant::from_std(deadline);
+        if tokio::time::timeout_at(deadline, &mut handle).await.is_err() {
+            println!("task did not stop within {}s, aborting it", STOP_TIMEOUT.as_secs());
+            handle.abort();
+            println!("task cancelled? {:?}", handle.await);
+        }
+    }
+}
+
+/// Every running task, keyed by the ID handed back from `!start-task`
+pub struct TaskRegistry {
+    next_id: TaskId,
+    tasks: BTreeMap<TaskId, Task>,
+    /// Shared by every task so they reuse one connection pool
+    anilist: AniListClient,
+    webhooks: WebhookClient,
+    data_dir: PathBuf,
+    /// Where tasks send problems the channel they were started from should hear about
+    notices: UnboundedSender<Notice>,
+}
+
+/// A message from a running task for the channel it was started from
+#[derive(Debug)]
+pub struct Notice {
+    pub task: TaskId,
+    pub channel_id: u64,
+    pub message: String,
+}
+
+impl TaskRegistry {
+    pub fn new(config: &Config, profiles: WebhookProfiles, notices: UnboundedSender<Notice>) -> Self {
+        TaskRegistry {
+            next_id: 0,
+            tasks: BTreeMap::new(),
+            anilist: AniListClient::new(&config.anilist_url),
+            webhooks: WebhookClient::new(&config.webhook_url, profiles),
+            data_dir: config.data_dir.clone(),
+            notices,
+        }
+    }
+
+    /// Spawns a new task for `job`, started from `channel_id`
+    pub fn start(&mut self, job: WebhookJob, channel_id: u64) -> Result<TaskId> {
+        job.validate(self.webhooks.profiles())?;
+        self.next_id += 1;
+        let id = self.next_id;
+        self.spawn(id, job, channel_id);
+        self.save();
+        Ok(id)
+    }
+
+    /// Restarts every saved task that is not already running,
+    /// returning their IDs alongside the channels they were started from
+    pub fn resume(&mut self) -> Result<Vec<(TaskId, u64)>> {
+        let saved: BTreeMap<TaskId, SavedTask> = load_json(&self.tasks_path())?.unwrap_or_default();
+        let mut resumed = vec![];
+        for (id, task) in saved {
+            if self.tasks.contains_key(&id) {
+                continue;
+            }
+            let mut job = task.job;
+            job.reset();
+            self.spawn(id, job, task.channel_id);
+            self.next_id = self.next_id.max(id);
+            resumed.push((id, task.channel_id));
+        }
+        Ok(resumed)
+    }
+
+    fn spawn(&mut self, id: TaskId, mut job: WebhookJob, channel_id: u64) {
+        // A task that cannot load its history still runs, it just may repeat old hits
+        if let Err(err) = job.restore(&self.data_dir) {
+            println!("could not restore seen entries: {:?}", err);
+        }
+        job.stats().lock().unwrap().started_at = Some(SystemTime::now());
+        let cancel = Arc::new(Cancel::default());
+        let handle = spawn_job(
+            id,
+            job.clone(),
+            channel_id,
+            cancel.clone(),
+            self.anilist.clone(),
+            self.webhooks.clone(),
+            self.notices.clone(),
+        );
+        self.tasks.insert(id, Task { job, channel_id, cancel, handle });
+    }
+
+    fn tasks_path(&self) -> PathBuf {
+        self.data_dir.join("tasks.json")
+    }
+
+    /// Writes the running tasks to disk, webhook tokens included
+    fn save(&self) {
+        let saved = self
+            .tasks
+            .iter()
+            .map(|(id, task)| {
+                (
+                    *id,
+                    SavedTask {
+                        channel_id: task.channel_id,
+                        job: task.job.clone(),
+                    },
+                )
+            })
+            .collect::<BTreeMap<_, _>>();
+        if let Err(err) = save_json(&self.tasks_path(), &saved) {
+            println!("could not save tasks: {:?}", err);
+        }
+    }
+
+    /// Returns false if no task had the given ID
+    pub async fn stop(&mut self, id: TaskId) -> bool {
+        match self.tasks.remove(&id) {
+            Some(task) => {
+                println!("cancelling task {}...", id);
+                task.stop().await;
+                self.save();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Stops every task, returning how many were running
+    pub async fn stop_all(&mut self) -> usize {
+        let tasks = std::mem::take(&mut self.tasks);
+        let count = tasks.len();
+        // Cancel them all first so they wind down together, sharing one timeout
+        for (id, task) in tasks.iter() {
+            println!("cancelling task {}...", id);
+            task.cancel.cancel();
+        }
+        let deadline = Instant::now() + STOP_TIMEOUT;
+        for task in tasks.into_values() {
+            task.join(deadline).await;
+        }
+        self.save();
+        count
+    }
+
+    /// A summary of every running task and of AniList throttling, for `!status`
+    pub fn status(&self) -> String {
+        let usage = self.anilist.rate_limit();
+        let mut requests = format!(
+            "AniList requests: {} of {} per minute available",
+            usage.available, usage.limit
+        );
+        if let Some(remaining) = usage.reported_remaining {
+            requests.push_str(&format!(" (AniList last reported {} left)", remaining));
+        }
+        let mut lines = vec![
+            requests,
+            match self.anilist.backoff() {
+                Some(backoff) => format!("AniList rate-limit backoff: {}s remaining", backoff.as_secs() + 1),
+                None => "AniList rate-limit backoff: none".to_string(),
+            },
+        ];
+        if self.tasks.is_empty() {
+            lines.push("No tasks are running.".to_string());
+        }
+        for (id, task) in self.tasks.iter() {
+            lines.push(String::new());
+            lines.extend(task.status(*id));
+        }
+        lines.join("\n")
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&TaskId, &Task)> {
+        self.tasks.iter()
+    }
+}
+
+/// How often hits still waiting to be delivered are retried between polls
+const FLUSH_INTERVAL: Duration = Duration::from_secs(2);
+/// How long a stopped task gets to finish its current poll before it is aborted
+const STOP_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn spawn_job(
+    id: TaskId,
+    mut job: WebhookJob,
+    channel_id: u64,
+    cancel: Arc<Cancel>,
+    anilist: AniListClient,
+    webhooks: WebhookClient,
+    notices: UnboundedSender<Notice>,
+) -> JoinHandle<()> {
+    tokio::task::spawn(async move {
+        let mut outage = Outage::default();
+        while !cancel.is_cancelled() && !job.stopped() {
+            let (interval, jitter) = job.interval();
+            // Clamped again since resumed tasks were never validated
+            let wait = util::with_jitter(interval.min(MAX_INTERVAL), jitter.min(MAX_INTERVAL));
+            let next_poll = Instant::now() + wait;
+            // Flush whatever the last poll couldn't deliver, unless the webhook is gone
+            while let Some(left) = next_poll.checked_duration_since(Instant::now()) {
+                tokio::select! {
+                    _ = tokio::time::sleep(left.min(FLUSH_INTERVAL)) => {}
+                    _ = cancel.wake.notified() => break,
+                }
+                if job.has_pending() && !outage.webhook_down {
+                    let delivered = job.deliver(&webhooks).await.map_err(anyhow::Error::from);
+                    outage.report(id, channel_id, &notices, delivered);
+                }
+            }
+            if cancel.is_cancelled() {
+                break;
+            }
+            let polled = job.poll(&anilist, &webhooks).await;
+            outage.report(id, channel_id, &notices, polled);
+        }
+        if job.stopped() && !cancel.is_cancelled() {
+            let error = job.stats().lock().unwrap().last_error.clone().unwrap_or_default();
+            let message = format!(
+                "Task {} stopped polling because AniList rejected its query: {}\n\
+                 Fix the job and start it again, and remove this one with `!stop-task {}`.",
+                id, error, id
+            );
+            let _ = notices.send(Notice { task: id, channel_id, message });
+        }
+        println!("[task {}] stopped", id);
+    })
+}
+
+/// Tracks whether a task's webhook is down, so the channel is told once per outage
+/// rather than on every attempt
+#[derive(Default)]
+struct Outage {
+    webhook_down: bool,
+}
+
+impl Outage {
+    fn report(&mut self, id: TaskId, channel_id: u64, notices: &UnboundedSender<Notice>, result: Result<()>) {
+        let err = match result {
+            Ok(()) => {
+                self.webhook_down = false;
+                return;
+            }
+            Err(err) => err,
+        };
+        println!("[task {}] error: {:?}", id, err);
+        match err.downcast_ref::<DeliveryError>() {
+            Some(DeliveryError::Unavailable(reason)) if !self.webhook_down => {
+                self.webhook_down = true;
+                let message = format!(
+                    "Task {} can't post to its webhook: {}\n\
+                     Hits are kept until it can. Save a working webhook under the same name with \
+                     `!webhook add`, or stop the task with `!stop-task {}`.",
+                    id, reason, id
+                );
+                let _ = notices.send(Notice { task: id, channel_id, message });
+            }
+            Some(DeliveryError::Unavailable(_)) => {}
+            _ => self.webhook_down = false,
+        }
+    }
+}
@@ -1,12 +1,40 @@
-pub fn wait(secs: u64) {
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+
+pub async fn wait(secs: u64) {
     println!("pausing for {}s...", secs);
-    let begin = std::time::Instant::now();
-    loop {
-        let since = std::time::Instant::now().checked_duration_since(begin);
-        if let Some(since) = since {
-            if since.as_secs() >= secs {
-                break;
-            }
-        }
-    }
-}
\ No newline at end of file
+    tokio::time::sleep(Duration::from_secs(secs)).await;
+}
+
+/// `secs` plus a random extra of up to `jitter` seconds,
+/// so tasks started at the same time don't keep polling in lockstep
+pub fn with_jitter(secs: u64, jitter: u64) -> Duration {
+    // A fresh RandomState is randomly keyed, which is plenty for spreading out polls
+    let extra = RandomState::new().build_hasher().finish() % jitter.saturating_mul(1000).saturating_add(1);
+    Duration::from_secs(secs).saturating_add(Duration::from_millis(extra))
+}
+
+/// Formats a Unix timestamp as an ISO 8601 date and time in UTC
+pub fn iso8601(timestamp: i64) -> String {
+    let (days, secs) = (timestamp.div_euclid(86400), timestamp.rem_euclid(86400));
+    // Turns days since 1970-01-01 into a date, counting in 400 year eras that start on March 1st
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_from_march + 2) / 5 + 1;
+    let month = if month_from_march < 10 { month_from_march + 3 } else { month_from_march - 9 };
+    let year = era * 400 + year_of_era + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
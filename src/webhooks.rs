@@ -1,8 +1,19 @@
 use crate::anilist::*;
-use anyhow::Result;
+use crate::config::DEFAULT_WEBHOOK_URL;
+use crate::keywords::Keyword;
+use crate::matches::*;
+use crate::normalize;
+use crate::profiles::{Webhook, WebhookProfiles};
+use crate::store::*;
+use crate::util;
+use anyhow::{anyhow, Result};
 use serde::{Deserialize, Serialize};
-use std::collections::{BTreeSet, HashMap};
-use reqwest::blocking::Client;
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use reqwest::{header::HeaderMap, Client, StatusCode};
 use crate::embeds::*;
 
 #[derive(Serialize, Clone)]
@@ -11,20 +22,217 @@ pub struct WebhookRequest {
 }
 
 impl WebhookRequest {
-    pub fn from(url: String, title: String, fields: Vec<(String, String)>) -> Self {
-        let mut embed = Embed::from(url, title);
-        for (name, value) in fields {
-            embed.fields.push(EmbedField::from(name, value));
-        }
+    pub fn from(url: String, title: String, matches: &[Match], score: Score) -> Self {
         WebhookRequest {
-            embeds: [embed].to_vec(),
+            embeds: vec![hit_embed(url, title, matches, score)],
         }
     }
 }
 
+/// An embed listing why something was flagged, ending with the score and what it is made of,
+/// and colored by severity
+fn hit_embed(url: String, title: String, matches: &[Match], score: Score) -> Embed {
+    let severity = score.severity();
+    let mut embed = Embed::from(url, title);
+    embed.color = Some(severity.color());
+    embed.footer = Some(EmbedFooter {
+        text: if score.escalated() {
+            format!("Escalated, severity: {}", severity)
+        } else {
+            format!("Severity: {}", severity)
+        },
+    });
+    // Leave room for the score, which comes last
+    embed.fields = matches.iter().take(MAX_FIELDS - 1).map(Match::field).collect();
+    embed.fields.push(score.field(matches));
+    embed
+}
+
+/// Drops the last match fields, keeping the score that comes after them, until the embed
+/// fits in a message on its own. Discord rejects anything longer outright.
+fn fit_message(embed: &mut Embed) {
+    while embed.text_len() > MAX_MESSAGE_TEXT && embed.fields.len() > 1 {
+        embed.fields.remove(embed.fields.len() - 2);
+    }
+}
+
+/// Puts `user`'s name and avatar at the top of `embed`, linked to their profile
+fn with_user(mut embed: Embed, user: &User) -> Embed {
+    let avatar = user.avatar.as_ref();
+    embed.author = Some(EmbedAuthor {
+        name: truncate(&user.name, MAX_AUTHOR_NAME_LEN),
+        url: user_url(user.id),
+        icon_url: avatar.and_then(|avatar| avatar.medium.clone()),
+    });
+    embed.thumbnail = avatar.and_then(|avatar| avatar.large.clone()).map(|url| EmbedImage { url });
+    embed
+}
+
+fn user_url(id: i32) -> String {
+    format!("https://anilist.co/user/{}", id)
+}
+
+fn activity_url(id: i32) -> String {
+    format!("https://anilist.co/activity/{}", id)
+}
+
+/// A markdown link to `user`'s profile
+fn user_link(user: &User) -> String {
+    format!("[{}]({})", escape_markdown(&user.name), user_url(user.id))
+}
 
+/// Attempts made to deliver a single message before leaving it for the next poll
+const MAX_DELIVERY_ATTEMPTS: u32 = 5;
+/// Most undelivered hits a job holds on to; older ones are dropped past this
+const MAX_PENDING_HITS: usize = 500;
 
-#[derive(Deserialize)]
+/// Why a webhook message could not be delivered
+#[derive(Debug)]
+pub enum DeliveryError {
+    /// The webhook was deleted, its token is wrong, or its profile is gone,
+    /// so nothing can be posted until someone fixes it
+    Unavailable(String),
+    /// Discord refused this particular message
+    Rejected(String),
+    /// Still failing after every retry, so it's worth trying again later
+    Failed(String),
+}
+
+impl fmt::Display for DeliveryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DeliveryError::Unavailable(reason) => write!(f, "webhook unavailable: {}", reason),
+            DeliveryError::Rejected(reason) => write!(f, "message rejected: {}", reason),
+            DeliveryError::Failed(reason) => write!(f, "delivery failed: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for DeliveryError {}
+
+/// Delivers embeds to Discord webhooks
+#[derive(Clone)]
+pub struct WebhookClient {
+    client: Client,
+    base_url: String,
+    profiles: WebhookProfiles,
+    /// Webhook URL -> when its rate-limit bucket refills, for buckets Discord said are empty
+    buckets: Arc<Mutex<HashMap<String, Instant>>>,
+}
+
+impl WebhookClient {
+    pub fn new(base_url: &str, profiles: WebhookProfiles) -> Self {
+        WebhookClient {
+            client: Client::new(),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            profiles,
+            buckets: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn profiles(&self) -> &WebhookProfiles {
+        &self.profiles
+    }
+
+    pub fn url(&self, webhook: &Webhook) -> String {
+        format!("{}/{}/{}", self.base_url, webhook.id, webhook.token)
+    }
+
+    /// Posts `embed`, waiting out Discord's rate limits and retrying server and network errors with backoff
+    pub async fn send_embed(&self, embed: &WebhookRequest, url: &str) -> Result<(), DeliveryError> {
+        let mut delay = 1;
+        let mut last_failure = String::new();
+        for attempt in 1..=MAX_DELIVERY_ATTEMPTS {
+            self.wait_for_bucket(url).await;
+            let resp = match self
+                .client
+                .post(url)
+                .header("Content-Type", "application/json")
+                .json(embed)
+                .send()
+                .await
+            {
+                Ok(resp) => resp,
+                Err(err) => {
+                    println!("webhook request failed (attempt {}): {}", attempt, err);
+                    last_failure = err.to_string();
+                    util::wait(delay).await;
+                    delay *= 2;
+                    continue;
+                }
+            };
+            self.update_bucket(url, resp.headers());
+            let status = resp.status();
+            if status.is_success() {
+                return Ok(());
+            }
+            // Always in seconds, unlike the body's `retry_after`, which older API versions give in milliseconds
+            let retry_after = header_secs(resp.headers(), "X-RateLimit-Reset-After")
+                .or_else(|| header_secs(resp.headers(), "Retry-After"));
+            let body = resp.text().await.unwrap_or_default();
+            last_failure = format!("HTTP {}: {}", status, body);
+            if status == StatusCode::TOO_MANY_REQUESTS {
+                let retry_after = retry_after
+                    .or_else(|| {
+                        serde_json::from_str::<serde_json::Value>(&body)
+                            .ok()
+                            .and_then(|body| body["retry_after"].as_f64())
+                    })
+                    .unwrap_or(1.0);
+                println!("webhook rate limited, retrying in {:.2}s", retry_after);
+                tokio::time::sleep(Duration::from_secs_f64(retry_after.max(0.0))).await;
+            } else if status.is_server_error() {
+                println!("webhook server error (attempt {}): {}", attempt, last_failure);
+                util::wait(delay).await;
+                delay *= 2;
+            } else if matches!(status.as_u16(), 401 | 403 | 404) {
+                return Err(DeliveryError::Unavailable(last_failure));
+            } else {
+                return Err(DeliveryError::Rejected(last_failure));
+            }
+        }
+        Err(DeliveryError::Failed(format!(
+            "gave up after {} attempts, last {}",
+            MAX_DELIVERY_ATTEMPTS, last_failure
+        )))
+    }
+
+    async fn wait_for_bucket(&self, url: &str) {
+        let until = self.buckets.lock().unwrap().get(url).copied();
+        if let Some(wait) = until.and_then(|until| until.checked_duration_since(Instant::now())) {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Remembers when the webhook's bucket refills if Discord says it is empty
+    fn update_bucket(&self, url: &str, headers: &HeaderMap) {
+        let remaining = headers
+            .get("X-RateLimit-Remaining")
+            .and_then(|val| val.to_str().ok())
+            .and_then(|val| val.parse::<u32>().ok());
+        let mut buckets = self.buckets.lock().unwrap();
+        match (remaining, header_secs(headers, "X-RateLimit-Reset-After")) {
+            (Some(0), Some(reset_after)) => {
+                buckets.insert(url.to_string(), Instant::now() + Duration::from_secs_f64(reset_after.max(0.0)));
+            }
+            _ => {
+                buckets.remove(url);
+            }
+        }
+    }
+}
+
+fn header_secs(headers: &HeaderMap, name: &str) -> Option<f64> {
+    headers.get(name)?.to_str().ok()?.parse().ok()
+}
+
+impl Default for WebhookClient {
+    fn default() -> Self {
+        WebhookClient::new(DEFAULT_WEBHOOK_URL, WebhookProfiles::default())
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone)]
 pub enum WebhookJob {
     /// Find a user's account
     User(Job<UserJob>),
@@ -32,115 +240,604 @@ pub enum WebhookJob {
     Activity(Job<ActivityJob>),
 }
 
-#[derive(Deserialize, Clone)]
+impl WebhookJob {
+    /// Forget everything the job has already reported
+    pub fn reset(&mut self) {
+        match self {
+            WebhookJob::User(job) => {
+                job.job.found_user_ids = Some(BTreeSet::new());
+            }
+            WebhookJob::Activity(job) => {
+                job.job.found_activity_ids = Some(BTreeSet::new());
+                job.job.found_activity_reply_ids = Some(HashMap::new());
+            }
+        }
+    }
+
+    pub async fn poll(&mut self, anilist: &AniListClient, webhooks: &WebhookClient) -> Result<()> {
+        match self {
+            WebhookJob::User(job) => job.poll(anilist, webhooks).await,
+            WebhookJob::Activity(job) => job.poll(anilist, webhooks).await,
+        }
+    }
+
+    /// Seconds between polls and the most random extra seconds to add to them
+    pub fn interval(&self) -> (u64, u64) {
+        match self {
+            WebhookJob::User(job) => (job.job.interval(), job.job.jitter()),
+            WebhookJob::Activity(job) => (job.job.interval(), job.job.jitter()),
+        }
+    }
+
+    pub fn stopped(&self) -> bool {
+        match self {
+            WebhookJob::User(job) => job.stopped(),
+            WebhookJob::Activity(job) => job.stopped(),
+        }
+    }
+
+    pub fn has_pending(&self) -> bool {
+        match self {
+            WebhookJob::User(job) => !job.pending.is_empty(),
+            WebhookJob::Activity(job) => !job.pending.is_empty(),
+        }
+    }
+
+    pub async fn deliver(&mut self, webhooks: &WebhookClient) -> Result<(), DeliveryError> {
+        match self {
+            WebhookJob::User(job) => job.deliver(webhooks).await,
+            WebhookJob::Activity(job) => job.deliver(webhooks).await,
+        }
+    }
+
+    pub fn stats(&self) -> &Arc<Mutex<JobStats>> {
+        match self {
+            WebhookJob::User(job) => &job.stats,
+            WebhookJob::Activity(job) => &job.stats,
+        }
+    }
+
+    /// Checks that the job names a webhook that can be posted to and polls at least once a day
+    pub fn validate(&self, profiles: &WebhookProfiles) -> Result<()> {
+        let (interval, jitter) = self.interval();
+        if interval > MAX_INTERVAL || jitter > MAX_INTERVAL {
+            return Err(anyhow!("`interval` and `jitter` can be at most {} seconds", MAX_INTERVAL));
+        }
+        match self {
+            WebhookJob::User(job) => job.destination(profiles).map(|_| ()),
+            WebhookJob::Activity(job) => job.destination(profiles).map(|_| ()),
+        }
+    }
+
+    /// Loads what has already been reported to the job's webhook from `data_dir`,
+    /// and keeps recording new hits there so they survive restarts
+    pub fn restore(&mut self, data_dir: &Path) -> Result<()> {
+        match self {
+            WebhookJob::User(job) => {
+                let path = seen_path(data_dir, "user", &job.destination_key());
+                let found_user_ids = job.job.found_user_ids.get_or_insert_with(BTreeSet::new);
+                for entry in load_seen(&path)? {
+                    if let SeenEntry::User(id) = entry {
+                        found_user_ids.insert(id);
+                    }
+                }
+                job.seen_path = Some(path);
+            }
+            WebhookJob::Activity(job) => {
+                let path = seen_path(data_dir, "activity", &job.destination_key());
+                let found_activity_ids = job.job.found_activity_ids.get_or_insert_with(BTreeSet::new);
+                let found_reply_ids = job.job.found_activity_reply_ids.get_or_insert_with(HashMap::new);
+                for entry in load_seen(&path)? {
+                    match entry {
+                        SeenEntry::Activity(id) => {
+                            found_activity_ids.insert(id);
+                        }
+                        SeenEntry::Reply { id, activity_id } => {
+                            found_reply_ids.insert(id, activity_id);
+                        }
+                        SeenEntry::User(_) => {}
+                    }
+                }
+                job.seen_path = Some(path);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct Job<T> {
-    pub channel_id: String,
-    pub token: String,
+    /// Raw webhook ID, used along with `token` when no `webhook` profile is named
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channel_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+    /// Name of a stored webhook profile, which keeps the token out of chat
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub webhook: Option<String>,
     pub job: T,
+    /// Where reported hits are recorded, once the job has been restored
+    #[serde(skip)]
+    pub seen_path: Option<PathBuf>,
+    #[serde(skip)]
+    pub stats: Arc<Mutex<JobStats>>,
+    /// Hits found but not delivered yet, oldest first
+    #[serde(skip)]
+    pub pending: VecDeque<PendingHit>,
+}
+
+/// A hit waiting to be posted, and what to record as seen once it has been
+#[derive(Clone)]
+pub struct PendingHit {
+    pub seen: SeenEntry,
+    pub embed: Embed,
+}
+
+/// How many of the oldest pending hits fit in one message
+fn batch_len(pending: &VecDeque<PendingHit>) -> usize {
+    let mut text = 0;
+    let mut count = 0;
+    for hit in pending.iter().take(MAX_EMBEDS_PER_MESSAGE) {
+        text += hit.embed.text_len();
+        // A lone oversized hit is still sent, so Discord can reject it and it can be dropped
+        if count > 0 && text > MAX_MESSAGE_TEXT {
+            break;
+        }
+        count += 1;
+    }
+    count
+}
+
+/// Progress of a job, shared by every clone of it so `!status` can read it while it runs
+#[derive(Debug, Default, Clone)]
+pub struct JobStats {
+    pub started_at: Option<SystemTime>,
+    pub pages_scanned: u64,
+    pub items_checked: u64,
+    pub items_flagged: u64,
+    /// Flagged items whose score reached the escalation threshold
+    pub items_escalated: u64,
+    /// Users whose lists couldn't be checked because they are private
+    pub private_lists: u64,
+    pub last_error: Option<String>,
+    /// Set when AniList rejected the job's query, which retrying won't fix
+    pub stopped: bool,
+    /// Hits waiting to be delivered
+    pub pending_hits: usize,
+    /// Why delivery is currently failing, cleared once it works again
+    pub delivery_error: Option<String>,
 }
 
 impl<T> Job<T> {
-    pub fn send_embed_activity(&self, activity: Activity, matches: Vec<(String, String)>) -> Result<()> {
-        let url = self.url();
-        let req = WebhookRequest::from(
-            format!("https://anilist.co/activity/{}", activity.id),
-            "Activity".to_string(),
-            matches,
+    /// The webhook hits are posted to, looking up a named profile if there is one
+    pub fn destination(&self, profiles: &WebhookProfiles) -> Result<Webhook> {
+        match (&self.webhook, &self.channel_id, &self.token) {
+            (Some(name), _, _) => profiles
+                .get(name)
+                .ok_or_else(|| anyhow!("no webhook profile named \"{}\"", name)),
+            (None, Some(id), Some(token)) => Ok(Webhook {
+                id: id.clone(),
+                token: token.clone(),
+            }),
+            _ => Err(anyhow!("a job needs either a `webhook` profile name or a `channelId` and `token`")),
+        }
+    }
+
+    /// Identifies the destination without its secret, for keeping track of what was sent there
+    pub fn destination_key(&self) -> String {
+        match (&self.webhook, &self.channel_id) {
+            (Some(name), _) => format!("profile-{}", name),
+            (None, Some(id)) => id.clone(),
+            (None, None) => "unknown".to_string(),
+        }
+    }
+
+    fn record_sweep(&self, pages: i32, checked: usize, flagged: usize) {
+        let mut stats = self.stats.lock().unwrap();
+        stats.pages_scanned += pages as u64;
+        stats.items_checked += checked as u64;
+        stats.items_flagged += flagged as u64;
+    }
+
+    /// Records a failed sweep. Throttling and server trouble are retried on the next poll,
+    /// but a rejected query never will succeed, so the job stops.
+    fn record_error(&self, err: &anyhow::Error) {
+        let mut stats = self.stats.lock().unwrap();
+        stats.last_error = Some(format!("{:#}", err));
+        if let Some(AniListError::InvalidQuery(_)) = err.downcast_ref() {
+            stats.stopped = true;
+        }
+    }
+
+    /// Whether the job has given up polling, see `record_error`
+    pub fn stopped(&self) -> bool {
+        self.stats.lock().unwrap().stopped
+    }
+
+    fn record_seen(&self, entries: &[SeenEntry]) {
+        if let Some(path) = &self.seen_path {
+            if let Err(err) = append_seen(path, entries) {
+                println!("could not record seen entries in {:?}: {:?}", path, err);
+            }
+        }
+    }
+
+    pub fn queue_activity(&mut self, activity: Activity, matches: Matches, score: Score) {
+        let mut embed = with_user(
+            hit_embed(activity_url(activity.id), "Activity".to_string(), &matches, score),
+            &activity.user,
         );
-        Self::send_embed(&req, &url)//.await
+        embed.description = Some(match &activity.recipient {
+            Some(recipient) => format!("Message from {} to {}", user_link(&activity.user), user_link(recipient)),
+            None => format!("Posted by {}", user_link(&activity.user)),
+        });
+        embed.timestamp = activity.created_at.map(util::iso8601);
+        self.queue(SeenEntry::Activity(activity.id), embed, score);
     }
 
-    pub fn send_embed_user(&self, user: User, matches: Vec<(String, String)>) -> Result<()> {
-        let url = self.url();
-        let req = WebhookRequest::from(
-            format!("https://anilist.co/user/{}", user.id),
-            user.name,
-            matches,
+    pub fn queue_reply(&mut self, reply: ActivityReply, matches: Matches, score: Score) {
+        let mut embed = with_user(
+            hit_embed(activity_url(reply.activity_id), "Activity Reply".to_string(), &matches, score),
+            &reply.user,
         );
-        Self::send_embed(&req, &url)//.await
+        embed.description = Some(format!(
+            "Reply by {} to activity {}",
+            user_link(&reply.user),
+            reply.activity_id
+        ));
+        embed.timestamp = reply.created_at.map(util::iso8601);
+        let seen = SeenEntry::Reply {
+            id: reply.id,
+            activity_id: reply.activity_id,
+        };
+        self.queue(seen, embed, score);
+    }
+
+    pub fn queue_user(&mut self, user: User, matches: Matches, score: Score) {
+        let mut embed = with_user(hit_embed(user_url(user.id), user.name.clone(), &matches, score), &user);
+        embed.description = user.created_at.map(|joined| format!("Joined <t:{}:R>", joined));
+        embed.timestamp = user.created_at.map(util::iso8601);
+        self.queue(SeenEntry::User(user.id), embed, score);
     }
 
-    pub fn send_embed(embed: &WebhookRequest, url: &str) -> Result<()> {
-        let client = Client::new();
-        let _ = client
-            .post(url)
-            .header("Content-Type", "application/json")
-            .json(embed)
-            .send()?;
-            // .await?;
+    fn queue(&mut self, seen: SeenEntry, mut embed: Embed, score: Score) {
+        fit_message(&mut embed);
+        if score.escalated() {
+            self.stats.lock().unwrap().items_escalated += 1;
+        }
+        if self.pending.len() >= MAX_PENDING_HITS {
+            println!("too many undelivered hits, dropping the oldest");
+            self.pending.pop_front();
+        }
+        self.pending.push_back(PendingHit { seen, embed });
+    }
+
+    /// Posts pending hits in order, as many to a message as Discord allows,
+    /// recording each as seen once it is delivered. Stops at the first message
+    /// that can't be delivered yet, keeping it and the rest for later.
+    pub async fn deliver(&mut self, webhooks: &WebhookClient) -> Result<(), DeliveryError> {
+        let result = self.deliver_pending(webhooks).await;
+        let mut stats = self.stats.lock().unwrap();
+        stats.pending_hits = self.pending.len();
+        stats.delivery_error = result.as_ref().err().map(|err| err.to_string());
+        result
+    }
+
+    async fn deliver_pending(&mut self, webhooks: &WebhookClient) -> Result<(), DeliveryError> {
+        while !self.pending.is_empty() {
+            let url = self
+                .url(webhooks)
+                .map_err(|err| DeliveryError::Unavailable(err.to_string()))?;
+            let count = batch_len(&self.pending);
+            match self.send_pending(webhooks, &url, count).await {
+                Ok(()) => self.finish_pending(count),
+                Err(DeliveryError::Rejected(reason)) if count > 1 => {
+                    // Send the batch one at a time to find which hit Discord objects to
+                    println!("batch rejected ({}), sending its hits separately", reason);
+                    for _ in 0..count {
+                        match self.send_pending(webhooks, &url, 1).await {
+                            Ok(()) => {}
+                            Err(DeliveryError::Rejected(reason)) => println!("dropping hit Discord rejected: {}", reason),
+                            Err(err) => return Err(err),
+                        }
+                        self.finish_pending(1);
+                    }
+                }
+                // Retrying won't change Discord's mind, so let it go
+                Err(DeliveryError::Rejected(reason)) => {
+                    println!("dropping hit Discord rejected: {}", reason);
+                    self.finish_pending(1);
+                }
+                Err(err) => return Err(err),
+            }
+        }
         Ok(())
     }
 
-    pub fn url(&self) -> String {
-        format!("https://discord.com/api/webhooks/{}/{}", self.channel_id, self.token)
+    /// Posts the oldest `count` pending hits as one message
+    async fn send_pending(&self, webhooks: &WebhookClient, url: &str, count: usize) -> Result<(), DeliveryError> {
+        let request = WebhookRequest {
+            embeds: self.pending.iter().take(count).map(|hit| hit.embed.clone()).collect(),
+        };
+        webhooks.send_embed(&request, url).await
+    }
+
+    /// Drops the oldest `count` pending hits, recording them as seen
+    fn finish_pending(&mut self, count: usize) {
+        let seen = self.pending.drain(..count).map(|hit| hit.seen).collect::<Vec<_>>();
+        self.record_seen(&seen);
+    }
+
+    pub fn url(&self, webhooks: &WebhookClient) -> Result<String> {
+        Ok(webhooks.url(&self.destination(webhooks.profiles())?))
     }
 }
 
-#[derive(Deserialize, Clone)]
+impl Job<ActivityJob> {
+    /// Runs a single sweep of recent activities and delivers every new hit,
+    /// along with any left over from earlier polls
+    pub async fn poll(&mut self, anilist: &AniListClient, webhooks: &WebhookClient) -> Result<()> {
+        let found = match self.job.find_activities(anilist).await {
+            Ok(hits) => {
+                self.record_sweep(hits.pages, hits.checked, hits.activities.len() + hits.replies.len());
+                for (activity, matches) in hits.activities {
+                    let score = self.job.score(&matches);
+                    self.queue_activity(activity, matches, score);
+                }
+                for (reply, matches) in hits.replies {
+                    let score = self.job.score(&matches);
+                    self.queue_reply(reply, matches, score);
+                }
+                Ok(())
+            }
+            Err(err) => {
+                self.record_error(&err);
+                Err(err)
+            }
+        };
+        self.deliver(webhooks).await?;
+        found
+    }
+}
+
+impl Job<UserJob> {
+    /// Runs a single sweep of new users and delivers every new hit,
+    /// along with any left over from earlier polls
+    pub async fn poll(&mut self, anilist: &AniListClient, webhooks: &WebhookClient) -> Result<()> {
+        let found = match self.job.find_users(anilist).await {
+            Ok(hits) => {
+                self.record_sweep(hits.pages, hits.checked, hits.users.len());
+                self.stats.lock().unwrap().private_lists += hits.private_lists.len() as u64;
+                for (user, matches) in hits.users {
+                    let score = self.job.score(&matches);
+                    self.queue_user(user, matches, score);
+                }
+                Ok(())
+            }
+            Err(err) => {
+                self.record_error(&err);
+                Err(err)
+            }
+        };
+        self.deliver(webhooks).await?;
+        found
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone)]
 pub struct ActivityJob {
     /// Look through new activities for keywords
-    pub keywords: Vec<String>,
+    pub keywords: Vec<Keyword>,
     /// Optionally check the user's profile & list as well.
     pub user_job: Option<UserJob>,
+    /// Also look through the replies of activities that have any
+    #[serde(default)]
+    pub replies: bool,
+    /// How many pages of recent activities to look through (1 by default)
+    pub depth: Option<i32>,
+    /// Seconds between polls (10 by default)
+    pub interval: Option<u64>,
+    /// Up to this many extra seconds added to each pause at random (2 by default)
+    pub jitter: Option<u64>,
+    /// Fold lookalike characters and leetspeak before matching keywords
+    #[serde(default)]
+    pub normalize: bool,
+    /// Lowest score reported (1 by default, so any match is)
+    #[serde(rename = "reportScore")]
+    pub report_score: Option<u32>,
+    /// Score at which a hit is escalated; hits are never escalated without it
+    #[serde(rename = "escalateScore")]
+    pub escalate_score: Option<u32>,
     /// Activity IDs (parent) found
+    #[serde(skip_serializing)]
     pub found_activity_ids: Option<BTreeSet<i32>>,
     /// Activity reply ID -> parent activity ID
-    _found_activity_reply_ids: Option<HashMap<i32, i32>>,
+    #[serde(skip_serializing)]
+    pub found_activity_reply_ids: Option<HashMap<i32, i32>>,
+    /// Highest activity ID seen by the last poll, which the next one pages back to
+    #[serde(skip)]
+    pub newest_activity_id: Option<i32>,
+}
+
+/// Most pages a single poll will go back to catch up with the previous one
+pub const MAX_CATCH_UP_PAGES: i32 = 20;
+/// Longest `interval` or `jitter` a job may have, in seconds
+pub const MAX_INTERVAL: u64 = 86_400;
+
+/// Flagged activities and replies from a single sweep of recent activities
+#[derive(Default)]
+pub struct ActivityHits {
+    pub activities: Vec<(Activity, Matches)>,
+    pub replies: Vec<(ActivityReply, Matches)>,
+    /// Pages of activities fetched
+    pub pages: i32,
+    /// Activities and replies looked at
+    pub checked: usize,
+}
+
+/// Flagged users from a single sweep of recent users
+#[derive(Default)]
+pub struct UserHits {
+    pub users: Vec<(User, Matches)>,
+    /// Pages of users fetched
+    pub pages: i32,
+    /// Users looked at
+    pub checked: usize,
+    /// Users whose lists are private
+    pub private_lists: Vec<i32>,
 }
 
 impl ActivityJob {
-    pub fn find_activities(&mut self, page: i32) -> Result<Vec<(Activity, Vec<(String, String)>)>> {
-        let mut matched_activities = vec![];
+    pub fn interval(&self) -> u64 {
+        self.interval.unwrap_or(10)
+    }
+
+    /// Scores a hit's matches, user matches included, against this job's thresholds
+    pub fn score(&self, matches: &[Match]) -> Score {
+        Score::of(matches, self.report_score, self.escalate_score)
+    }
+
+    pub fn jitter(&self) -> u64 {
+        self.jitter.unwrap_or(2)
+    }
+
+    pub async fn find_activities(&mut self, anilist: &AniListClient) -> Result<ActivityHits> {
+        let mut hits = ActivityHits::default();
         println!("checking activities");
-        let activity_resp = query_activities(page)?; //.await?;
-        if let Some(activity_page) = activity_resp.data {
-            let activities = activity_page.page;
-            if let Some(activities) = activities.page {
-                // Check each activity's content and user
-                for activity in activities {
-                    println!("{}", activity.id);
-
-                    // Check activity content
-                    if let Some(mut matches) = self.flag_activity(&activity) {
-                        println!("  flagged activity");
-                        // Check the username and bio
-                        if let Some(user_job) = &mut self.user_job {
-                            let user_matches = user_job.flag_user(&activity.user, &None);
-                            if let Some(user_matches) = user_matches {
-                                println!("user had stuff");
-                                for m in user_matches {
-                                    matches.push(m);
-                                }
-                            }
-                        }
-                        if let Some(found_activity_ids) = &mut self.found_activity_ids {
-                            println!("init");
-                            if !found_activity_ids.contains(&activity.id) {
-                                println!("not sent yet");
-                                found_activity_ids.insert(activity.id);
-                                matched_activities.push((activity, matches));
-                            } 
+        let since_id = self.newest_activity_id;
+        let activities = anilist
+            .query_activities(self.depth.unwrap_or(1), MAX_CATCH_UP_PAGES, since_id)
+            .await?;
+        hits.pages = activities.pages;
+        hits.checked = activities.items.len();
+        if let Some(since_id) = since_id {
+            if !activities.items.iter().any(|activity| activity.id <= since_id) {
+                println!(
+                    "could not catch up to activity {} within {} pages, some activities were missed",
+                    since_id, activities.pages
+                );
+            }
+        }
+        let newest = activities.items.iter().map(|activity| activity.id).max();
+        // Check each activity's content and user
+        for activity in activities.items {
+            println!("{}", activity.id);
+
+            if self.replies && activity.reply_count > 0 {
+                self.find_activity_replies(anilist, &activity, &mut hits).await?;
+            }
+
+            // Check activity content
+            if let Some(mut matches) = self.flag_activity(&activity) {
+                println!("  flagged activity");
+                // Check the username and bio
+                if let Some(user_job) = &mut self.user_job {
+                    let user_matches = user_job.flag_user(&activity.user, &None);
+                    if let Some(user_matches) = user_matches {
+                        println!("user had stuff");
+                        for m in user_matches {
+                            matches.push(m);
                         }
                     }
                 }
+                if !self.score(&matches).reported() {
+                    continue;
+                }
+                if let Some(found_activity_ids) = &self.found_activity_ids {
+                    println!("init");
+                    if !found_activity_ids.contains(&activity.id) {
+                        println!("not sent yet");
+                        hits.activities.push((activity, matches));
+                    } 
+                }
+            }
+        }
+        // Only remember hits and move the mark once every activity was checked,
+        // so a failed sweep is retried in full
+        if let Some(found_activity_ids) = &mut self.found_activity_ids {
+            found_activity_ids.extend(hits.activities.iter().map(|(activity, _)| activity.id));
+        }
+        if let Some(found_reply_ids) = &mut self.found_activity_reply_ids {
+            // Remember which parent each reply belonged to so it is only reported once
+            found_reply_ids.extend(hits.replies.iter().map(|(reply, _)| (reply.id, reply.activity_id)));
+        }
+        self.newest_activity_id = self.newest_activity_id.max(newest);
+
+        Ok(hits)
+    }
+
+    pub async fn find_activity_replies(
+        &mut self,
+        anilist: &AniListClient,
+        activity: &Activity,
+        hits: &mut ActivityHits,
+    ) -> Result<()> {
+        println!("checking replies of {}", activity.id);
+        let replies = match anilist.query_activity_replies(activity.id).await {
+            Ok(replies) => replies,
+            // Deleted since it was listed, so there's nothing left to check
+            Err(AniListError::NotFound(_)) => return Ok(()),
+            Err(err) => return Err(err.into()),
+        };
+        hits.checked += replies.len();
+        for reply in replies {
+            if let Some(mut matches) = self.flag_reply(&reply) {
+                println!("  flagged reply {}", reply.id);
+                if let Some(user_job) = &mut self.user_job {
+                    if let Some(user_matches) = user_job.flag_user(&reply.user, &None) {
+                        matches.extend(user_matches);
+                    }
+                }
+                if !self.score(&matches).reported() {
+                    continue;
+                }
+                if let Some(found_reply_ids) = &self.found_activity_reply_ids {
+                    if !found_reply_ids.contains_key(&reply.id) {
+                        hits.replies.push((reply, matches));
+                    }
+                }
             }
         }
 
-        Ok(matched_activities)
+        Ok(())
     }
 
-    pub fn flag_activity(&mut self, activity: &Activity) -> Option<Vec<(String, String)>> {
+    pub fn flag_activity(&mut self, activity: &Activity) -> Option<Matches> {
         let mut matches = Vec::new();
 
+        let text = normalize::prepare(&activity.text, self.normalize);
         for keyword in self.keywords.iter() {
-            let keyword = keyword.to_lowercase();
-            if activity.text.to_lowercase().contains(&keyword) {
-                matches.push(("Activity".to_string(), format!("Contained keyword: {}", keyword)));
-                matches.push(("User".to_string(), format!("https://anilist.co/user/{}", activity.user.id)));
-                if let Some(recipient) = activity.recipient.clone() {
-                    matches.push(("Message Recipient".to_string(), format!("https://anilist.co/user/{}", recipient.id)));
-                }
+            if let Some(range) = keyword.find(&text) {
+                let ids = EntityIds {
+                    user_id: activity.user.id,
+                    activity_id: Some(activity.id),
+                    ..EntityIds::default()
+                };
+                matches.push(Match::keyword(keyword, Target::Activity, text.original, range, ids));
+            }
+        }
+
+        if matches.is_empty() {
+            None
+        } else {
+            Some(matches)
+        }
+    }
+
+    pub fn flag_reply(&mut self, reply: &ActivityReply) -> Option<Matches> {
+        let mut matches = Vec::new();
+
+        let text = normalize::prepare(&reply.text, self.normalize);
+        for keyword in self.keywords.iter() {
+            if let Some(range) = keyword.find(&text) {
+                let ids = EntityIds {
+                    user_id: reply.user.id,
+                    activity_id: Some(reply.activity_id),
+                    reply_id: Some(reply.id),
+                    ..EntityIds::default()
+                };
+                matches.push(Match::keyword(keyword, Target::Reply, text.original, range, ids));
             }
         }
 
@@ -152,76 +849,150 @@ impl ActivityJob {
     }
 }
 
-#[derive(Deserialize, Clone)]
+#[derive(Deserialize, Serialize, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct UserJob {
     /// Check name and bio for keywords
-    pub keywords: Vec<String>,
+    pub keywords: Vec<Keyword>,
     /// Check the user's list entries for poorly rated media
     pub media_ids: Vec<i32>,
-    /// How many multiples of 50 to look backwards for Users
+    /// Most pages of users a sweep looks backwards through
     pub depth: i32,
+    /// Look through all `depth` pages on the first sweep, not just the newest page
+    #[serde(default)]
+    pub full_sweep: bool,
+    /// Seconds between sweeps (30 by default)
+    pub interval: Option<u64>,
+    /// Up to this many extra seconds added to each pause at random (2 by default)
+    pub jitter: Option<u64>,
     /// The score to check for (or below)
     pub max_score_threshold: Option<i32>,
+    /// Fold lookalike characters and leetspeak before matching keywords
+    #[serde(default)]
+    pub normalize: bool,
+    /// Added to the score for each list entry scored at or below `max_score_threshold` (1 by default)
+    pub low_score_weight: Option<u32>,
+    /// Lowest score reported (1 by default, so any match is)
+    pub report_score: Option<u32>,
+    /// Score at which a hit is escalated; hits are never escalated without it
+    pub escalate_score: Option<u32>,
     /// To keep track of already flagged uers
+    #[serde(skip_serializing)]
     pub found_user_ids: Option<BTreeSet<i32>>,
+    /// Highest user ID processed so far; later sweeps only look at newer users
+    #[serde(skip)]
+    pub newest_user_id: Option<i32>,
 }
 
 impl UserJob {
-    pub fn find_users(&mut self, page: i32) -> Result<Vec<(User, Vec<(String, String)>)>> {
-        let mut found_users = vec![];
-
-        let user_resp = query_users(page)?; //.await?;
-        if let Some(user_page) = user_resp.data {
-            let users = user_page.page;
-            if let Some(users) = users.page {
-                // Check each user's info and list entries
-                for user in users {
-                    println!("{:#?}", user);
-                    // Only check list entries if it was requested
-                    let list = if !self.media_ids.is_empty() {
-                        let list_resp = query_in_media_list(user.id, &self.media_ids)?; //.await?;
-                        match list_resp.data {
-                            Some(data) => data.page.page,
-                            None => None,
-                        }
-                    } else {
+    pub fn interval(&self) -> u64 {
+        self.interval.unwrap_or(30)
+    }
+
+    pub fn score(&self, matches: &[Match]) -> Score {
+        Score::of(matches, self.report_score, self.escalate_score)
+    }
+
+    pub fn jitter(&self) -> u64 {
+        self.jitter.unwrap_or(2)
+    }
+
+    pub async fn find_users(&mut self, anilist: &AniListClient) -> Result<UserHits> {
+        let mut hits = UserHits::default();
+
+        let since_id = self.newest_user_id;
+        // Only the first sweep of a full sweep job goes deep, later ones just catch up
+        let min_pages = if since_id.is_none() && self.full_sweep { self.depth } else { 1 };
+        let users = anilist.query_users(min_pages, self.depth, since_id).await?;
+        hits.pages = users.pages;
+        if let Some(since_id) = since_id {
+            if !users.items.iter().any(|user| user.id <= since_id) {
+                println!(
+                    "could not catch up to user {} within {} pages, some users were missed",
+                    since_id, users.pages
+                );
+            }
+        }
+        let newest = users.items.iter().map(|user| user.id).max();
+        let users = users
+            .items
+            .into_iter()
+            .filter(|user| !matches!(since_id, Some(since_id) if user.id <= since_id))
+            .collect::<Vec<_>>();
+        hits.checked = users.len();
+        // Check each user's info and list entries
+        for user in users {
+            println!("{:#?}", user);
+            // Only check list entries if it was requested
+            let mut private = false;
+            let list = if !self.media_ids.is_empty() {
+                match anilist.query_in_media_list(user.id, &self.media_ids).await {
+                    Ok(list) => Some(list),
+                    Err(AniListError::PrivateList(_)) => {
+                        println!("  list of {} is private", user.id);
+                        private = true;
+                        hits.private_lists.push(user.id);
                         None
-                    };
-                    println!("{:#?}", list);
-
-                    // Check if user should be flagged or has been already flagged
-                    if let Some(matches) =  self.flag_user(&user, &list) {
-                        if let Some(found_user_ids) = &mut self.found_user_ids {
-                            if !found_user_ids.contains(&user.id) {
-                                found_user_ids.insert(user.id);
-                                found_users.push((user, matches));
-                            } 
-                        }
                     }
+                    // Deleted since it was listed
+                    Err(AniListError::NotFound(_)) => continue,
+                    Err(err) => return Err(err.into()),
+                }
+            } else {
+                None
+            };
+            println!("{:#?}", list);
+
+            // Check if user should be flagged or has been already flagged
+            if let Some(mut matches) = self.flag_user(&user, &list) {
+                if private {
+                    let ids = EntityIds {
+                        user_id: user.id,
+                        ..EntityIds::default()
+                    };
+                    matches.push(Match::new(Rule::PrivateList, Target::ListEntry, None, ids, 0));
+                }
+                if !self.score(&matches).reported() {
+                    continue;
+                }
+                if let Some(found_user_ids) = &self.found_user_ids {
+                    if !found_user_ids.contains(&user.id) {
+                        hits.users.push((user, matches));
+                    } 
                 }
             }
         }
+        // Only remember hits and move the mark once every user was checked,
+        // so a failed sweep is retried in full
+        if let Some(found_user_ids) = &mut self.found_user_ids {
+            found_user_ids.extend(hits.users.iter().map(|(user, _)| user.id));
+        }
+        self.newest_user_id = self.newest_user_id.max(newest);
 
-        Ok(found_users)
+        Ok(hits)
     }
 
-    pub fn flag_user(&mut self, user: &User, matched_entries: &Option<Vec<MediaList>>) -> Option<Vec<(String, String)>> {
+    pub fn flag_user(&mut self, user: &User, matched_entries: &Option<Vec<MediaList>>) -> Option<Matches> {
         let mut matches = Vec::new();
+        let name = normalize::prepare(&user.name, self.normalize);
+        let about = user.about.as_ref().map(|about| normalize::prepare(about, self.normalize));
+        let ids = EntityIds {
+            user_id: user.id,
+            ..EntityIds::default()
+        };
         for keyword in self.keywords.iter() {
-            let keyword = keyword.to_lowercase();
-            if user.name.to_lowercase().contains(&keyword) {
-                matches.push(("Username".to_string(), format!("Username contained keyword: {}", keyword)));
+            if let Some(range) = keyword.find(&name) {
+                matches.push(Match::keyword(keyword, Target::Username, name.original, range, ids.clone()));
             }
-            if let Some(about) = &user.about {
-                if about.to_lowercase().contains(&keyword) {
-                    matches.push(("Bio".to_string(), format!("Bio contained keyword: {}", keyword)));
+            if let Some(about) = &about {
+                if let Some(range) = keyword.find(about) {
+                    matches.push(Match::keyword(keyword, Target::Bio, about.original, range, ids.clone()));
                 }
             }
         }
         if let Some(entries) = matched_entries {
             println!("got entries");
-            if let Some(user_matches) = self.flag_user_entries(entries) {
+            if let Some(user_matches) = self.flag_user_entries(user.id, entries) {
                 for m in user_matches.into_iter() {
                     matches.push(m);
                 }
@@ -234,14 +1005,20 @@ impl UserJob {
         }
     }
 
-    pub fn flag_user_entries(&mut self, entries: &Vec<MediaList>) -> Option<Vec<(String, String)>> {
+    pub fn flag_user_entries(&mut self, user_id: i32, entries: &[MediaList]) -> Option<Matches> {
         let max_score = self.max_score_threshold?;
         let mut matches = Vec::new();
         for entry in entries {
             if let Some(score) = entry.score {
                 println!("{} <= {}", score, max_score);
                 if score <= max_score as f64 && score != 0 as f64 {
-                    matches.push(("List Entry Score".to_string(), format!("Media ID poorly scored: {}", entry.media_id)));
+                    let ids = EntityIds {
+                        user_id,
+                        media_id: Some(entry.media_id),
+                        ..EntityIds::default()
+                    };
+                    let weight = self.low_score_weight.unwrap_or(1);
+                    matches.push(Match::new(Rule::LowListScore(max_score), Target::ListEntry, None, ids, weight));
                 }
             }
         }
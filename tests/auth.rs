@@ -0,0 +1,61 @@
+use al_raid_bot::auth::{parse_ids, Allowlist, Denial, Origin};
+
+fn allowlist(users: &str, roles: &str, channels: &str) -> Allowlist {
+    Allowlist {
+        user_ids: parse_ids("users", users),
+        role_ids: parse_ids("roles", roles),
+        channel_ids: parse_ids("channels", channels),
+    }
+}
+
+#[test]
+fn empty_allowlist_allows_everyone() {
+    assert_eq!(allowlist("", "", "").check(1, &[], Origin::Channel(2)), Ok(()));
+}
+
+#[test]
+fn users_are_allowed_directly_or_through_a_role() {
+    let allowlist = allowlist("10, 11", "20", "");
+    assert_eq!(allowlist.check(10, &[], Origin::Channel(1)), Ok(()));
+    assert_eq!(allowlist.check(12, &[30, 20], Origin::Channel(1)), Ok(()));
+    assert_eq!(allowlist.check(12, &[30], Origin::Channel(1)), Err(Denial::User));
+    assert!(!allowlist.needs_roles(11));
+    assert!(allowlist.needs_roles(12));
+}
+
+#[test]
+fn channels_are_checked_before_users() {
+    let allowlist = allowlist("10", "", "100");
+    assert_eq!(allowlist.check(10, &[], Origin::Channel(100)), Ok(()));
+    assert_eq!(allowlist.check(10, &[], Origin::Channel(101)), Err(Denial::Channel));
+    assert_eq!(allowlist.check(11, &[], Origin::Channel(101)), Err(Denial::Channel));
+}
+
+#[test]
+fn direct_messages_need_a_user_allowed_by_id() {
+    let dm = Origin::Direct { dm_only: false };
+    let dm_only = Origin::Direct { dm_only: true };
+
+    let restricted = allowlist("10", "20", "100");
+    assert_eq!(restricted.check(10, &[], dm), Ok(()));
+    assert_eq!(restricted.check(11, &[20], dm), Err(Denial::DirectMessage));
+    // `!webhook add` only works in DMs, so users allowed through a role can still use it
+    assert_eq!(restricted.check(11, &[20], dm_only), Ok(()));
+    assert_eq!(restricted.check(11, &[], dm_only), Err(Denial::User));
+
+    assert_eq!(allowlist("", "", "").check(11, &[], dm), Ok(()));
+}
+
+#[test]
+fn a_channel_only_allowlist_refuses_direct_messages() {
+    let allowlist = allowlist("", "", "100");
+    assert_eq!(allowlist.check(11, &[], Origin::Channel(100)), Ok(()));
+    assert_eq!(allowlist.check(11, &[], Origin::Direct { dm_only: false }), Err(Denial::DirectMessage));
+    assert_eq!(allowlist.check(11, &[], Origin::Direct { dm_only: true }), Err(Denial::DirectMessage));
+}
+
+#[test]
+#[should_panic(expected = "users must be a comma separated list of IDs")]
+fn invalid_ids_are_rejected() {
+    parse_ids("users", "10,mod");
+}
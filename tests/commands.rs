@@ -0,0 +1,124 @@
+use al_raid_bot::commands::{describe_json_error, is_command, parse_sweep, parse_watch, CommandError, SWEEP_USAGE, WATCH_USAGE};
+use al_raid_bot::keywords::KeywordKind;
+use al_raid_bot::webhooks::WebhookJob;
+
+fn error(result: Result<WebhookJob, CommandError>) -> CommandError {
+    match result {
+        Ok(_) => panic!("expected the command to be rejected"),
+        Err(err) => err,
+    }
+}
+
+#[test]
+fn watch_builds_an_activity_job() {
+    let job = parse_watch(r#"activities keywords="free nitro,word:the,regex:n\\d+" webhook=mod-log replies=yes depth=3"#)
+        .unwrap();
+    let job = match job {
+        WebhookJob::Activity(job) => job,
+        _ => panic!("expected an activity job"),
+    };
+    assert_eq!(job.webhook.as_deref(), Some("mod-log"));
+    assert!(job.job.replies);
+    assert_eq!(job.job.depth, Some(3));
+    let keywords = job
+        .job
+        .keywords
+        .iter()
+        .map(|keyword| (keyword.kind, keyword.pattern.as_str()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        keywords,
+        vec![
+            (KeywordKind::Literal, "free nitro"),
+            (KeywordKind::Word, "the"),
+            (KeywordKind::Regex, r"n\d+"),
+        ]
+    );
+}
+
+#[test]
+fn a_regex_keyword_keeps_its_commas() {
+    let job = parse_watch(r#"activities keywords="spam,regex:(https?://\\S+\\s*){3,}" webhook=mod-log"#).unwrap();
+    let job = match job {
+        WebhookJob::Activity(job) => job,
+        _ => panic!("expected an activity job"),
+    };
+    let keywords = job
+        .job
+        .keywords
+        .iter()
+        .map(|keyword| (keyword.kind, keyword.pattern.as_str()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        keywords,
+        vec![(KeywordKind::Literal, "spam"), (KeywordKind::Regex, r"(https?://\S+\s*){3,}")]
+    );
+}
+
+#[test]
+fn sweep_builds_a_user_job() {
+    let job = parse_sweep(
+        "users depth=10 media=121,999 max-score=30 keywords=spam webhook=mod-log report-score=2 escalate-score=5",
+    )
+    .unwrap();
+    let job = match job {
+        WebhookJob::User(job) => job,
+        _ => panic!("expected a user job"),
+    };
+    assert_eq!(job.job.depth, 10);
+    assert_eq!(job.job.media_ids, vec![121, 999]);
+    assert_eq!(job.job.max_score_threshold, Some(30));
+    assert_eq!((job.job.report_score, job.job.escalate_score), (Some(2), Some(5)));
+    assert_eq!(job.job.keywords.len(), 1);
+}
+
+#[test]
+fn parse_errors_come_with_usage() {
+    let err = error(parse_sweep("users depth=ten webhook=mod-log"));
+    assert!(err.message.contains("`depth` must be a whole number"));
+    assert_eq!(err.usage, SWEEP_USAGE);
+
+    let err = error(parse_watch(r#"activities keywords="spam webhook=mod-log"#));
+    assert_eq!(err.message, "A quote was never closed.");
+    assert!(err.to_string().contains(WATCH_USAGE));
+
+    assert!(error(parse_watch("activities keywords=spam")).message.contains("`webhook`"));
+    assert!(parse_watch("users keywords=spam webhook=mod-log").is_err());
+    assert!(error(parse_watch("activities keywords=spam webhook=mod-log colour=red"))
+        .message
+        .contains("Unknown option `colour`"));
+}
+
+#[test]
+fn json_errors_point_at_the_field() {
+    let body = "{\n    \"User\": {\n        \"webhook\": \"mod-log\",\n        \"job\": {\n            \"keywords\": [],\n            \"mediaIds\": [],\n            \"depth\": \"ten\"\n        }\n    }\n}";
+    let err = serde_json::from_str::<WebhookJob>(body).err().unwrap();
+    let description = describe_json_error(body, &err);
+    assert!(description.starts_with("Line 7, column 26: invalid type: string \"ten\""));
+    assert!(description.contains("            \"depth\": \"ten\"\n                         ^"));
+    assert!(description.ends_with("Check the value given for `depth`."));
+
+    let body = "{\"User\": {\"webhook\": \"mod-log\" \"job\": {}}}";
+    let err = serde_json::from_str::<WebhookJob>(body).err().unwrap();
+    assert!(describe_json_error(body, &err).ends_with("or a trailing comma, just before here."));
+}
+
+#[test]
+fn json_errors_inside_non_ascii_text_do_not_panic() {
+    for body in ["{“User”: {}}", "{\"a\": é}", "{\"User\": {\"webhook\": \"ñandú\", \"job\": é}}"].iter() {
+        let err = serde_json::from_str::<WebhookJob>(body).err().unwrap();
+        let description = describe_json_error(body, &err);
+        assert!(description.starts_with("Line 1, column "));
+        assert!(description.contains(body));
+    }
+}
+
+#[test]
+fn only_the_bots_own_commands_are_recognised() {
+    for cmd in ["!start-task", "!watch", "!sweep", "!stop-task", "!status", "!webhook"].iter() {
+        assert!(is_command(cmd), "{} should be a command", cmd);
+    }
+    for cmd in ["!play", "!", "!!!", "!statuses", "status"].iter() {
+        assert!(!is_command(cmd), "{} should not be a command", cmd);
+    }
+}
@@ -0,0 +1,67 @@
+//! Helpers shared by the integration tests
+
+// Each test crate only uses some of these
+#![allow(dead_code)]
+
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Request, Response, Server,
+};
+use serde_json::Value;
+use std::{
+    convert::Infallible,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+/// Requests received by a stub server, as `(path, json body)`
+pub type Received = Arc<Mutex<Vec<(String, Value)>>>;
+
+/// Starts a server on a random port that records every request body and
+/// answers with whatever `respond` returns for it
+pub fn stub_server<F>(respond: F) -> (String, Received)
+where
+    F: Fn(&Value) -> Value + Send + Sync + 'static,
+{
+    stub_server_with_status(move |body| (200, respond(body)))
+}
+
+/// Like `stub_server`, but `respond` also picks the HTTP status
+pub fn stub_server_with_status<F>(respond: F) -> (String, Received)
+where
+    F: Fn(&Value) -> (u16, Value) + Send + Sync + 'static,
+{
+    let received: Received = Arc::new(Mutex::new(vec![]));
+    let respond = Arc::new(respond);
+    let make_svc = {
+        let received = received.clone();
+        make_service_fn(move |_| {
+            let received = received.clone();
+            let respond = respond.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    let received = received.clone();
+                    let respond = respond.clone();
+                    async move {
+                        let path = req.uri().path().to_string();
+                        let bytes = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                        let body: Value = serde_json::from_slice(&bytes).unwrap_or(Value::Null);
+                        let (status, reply) = respond(&body);
+                        received.lock().unwrap().push((path, body));
+                        Ok::<_, Infallible>(
+                            Response::builder()
+                                .status(status)
+                                .header("Content-Type", "application/json")
+                                .body(Body::from(reply.to_string()))
+                                .unwrap(),
+                        )
+                    }
+                }))
+            }
+        })
+    };
+    let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_svc);
+    let url = format!("http://{}", server.local_addr());
+    tokio::spawn(server);
+    (url, received)
+}
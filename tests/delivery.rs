@@ -0,0 +1,231 @@
+//! Webhook delivery against a stub Discord that fails on cue
+
+use al_raid_bot::{
+    anilist::User,
+    keywords::{Keyword, KeywordKind},
+    matches::*,
+    profiles::WebhookProfiles,
+    store::{load_seen, SeenEntry},
+    webhooks::*,
+};
+use common::stub_server_with_status;
+use serde_json::{json, Value};
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+mod common;
+
+/// A webhook that answers with `statuses` in turn, then 204s
+fn stub_webhook(statuses: Vec<(u16, Value)>) -> (String, common::Received) {
+    let statuses = Arc::new(Mutex::new(VecDeque::from(statuses)));
+    stub_server_with_status(move |_| statuses.lock().unwrap().pop_front().unwrap_or((204, Value::Null)))
+}
+
+fn user_job(data_dir: &str) -> Job<UserJob> {
+    let mut job: WebhookJob = serde_json::from_value(json!({
+        "User": {
+            "channelId": "123",
+            "token": "secret",
+            "job": { "keywords": ["spam"], "mediaIds": [], "depth": 1 }
+        }
+    }))
+    .unwrap();
+    let data_dir = std::env::temp_dir().join(format!("al-raid-bot-{}-{}", data_dir, std::process::id()));
+    let _ = std::fs::remove_dir_all(&data_dir);
+    job.reset();
+    job.restore(&data_dir).unwrap();
+    match job {
+        WebhookJob::User(job) => job,
+        _ => unreachable!(),
+    }
+}
+
+fn user(id: i32) -> User {
+    User {
+        id,
+        name: format!("user{}", id),
+        about: None,
+        created_at: None,
+        avatar: None,
+    }
+}
+
+/// A match for "spam" in a bio of `text`
+fn bio(text: &str) -> Match {
+    let keyword = Keyword::new(KeywordKind::Literal, "spam").unwrap();
+    let start = text.find("spam").unwrap();
+    Match::keyword(&keyword, Target::Bio, text, start..start + 4, EntityIds::default())
+}
+
+fn matches() -> Matches {
+    vec![bio("spam")]
+}
+
+fn score(matches: &[Match]) -> Score {
+    Score::of(matches, None, None)
+}
+
+#[test]
+fn huge_weights_saturate_instead_of_overflowing() {
+    let mut heavy = bio("spam");
+    heavy.weight = u32::MAX;
+    let matches = vec![heavy.clone(), heavy];
+    let score = Score::of(&matches, None, Some(u32::MAX));
+    assert_eq!(score.total, u32::MAX);
+    assert!(score.escalated());
+}
+
+#[test]
+fn matches_carry_a_stable_rule_id() {
+    let regex = Keyword::new(KeywordKind::Regex, r"n\d+").unwrap();
+    let hits = [
+        bio("spam"),
+        Match::keyword(&regex, Target::Bio, "n1", 0..2, EntityIds::default()),
+        Match::new(Rule::LowListScore(30), Target::ListEntry, None, EntityIds::default(), 1),
+        Match::new(Rule::PrivateList, Target::ListEntry, None, EntityIds::default(), 0),
+    ];
+    let ids = hits
+        .iter()
+        .map(|hit| serde_json::to_value(hit).unwrap()["ruleId"].as_str().unwrap().to_string())
+        .collect::<Vec<_>>();
+    assert_eq!(ids, vec!["literal:spam", r"regex:n\d+", "low-list-score:30", "private-list"]);
+}
+
+#[tokio::test]
+async fn rate_limits_and_server_errors_are_retried() {
+    let (url, received) = stub_webhook(vec![
+        (429, json!({ "message": "You are being rate limited.", "retry_after": 0.2, "global": false })),
+        (502, Value::Null),
+    ]);
+    let webhooks = WebhookClient::new(&url, WebhookProfiles::default());
+    let matches = matches();
+    let request = WebhookRequest::from(
+        "https://anilist.co/user/1".to_string(),
+        "user1".to_string(),
+        &matches,
+        score(&matches),
+    );
+
+    let started = Instant::now();
+    webhooks.send_embed(&request, &format!("{}/123/secret", url)).await.unwrap();
+    // 0.2s for the rate limit, then 1s of backoff after the server error
+    assert!(started.elapsed() >= Duration::from_millis(1200));
+    assert_eq!(received.lock().unwrap().len(), 3);
+}
+
+#[tokio::test]
+async fn hits_are_kept_until_the_webhook_works_again() {
+    let (url, received) = stub_webhook(vec![
+        (404, json!({ "message": "Unknown Webhook", "code": 10015 })),
+        (400, json!({ "message": "Invalid Form Body" })),
+        (204, Value::Null),
+        (400, json!({ "message": "Invalid Form Body" })),
+    ]);
+    let webhooks = WebhookClient::new(&url, WebhookProfiles::default());
+    let mut job = user_job("delivery");
+    job.queue_user(user(1), matches(), score(&matches()));
+    job.queue_user(user(2), matches(), score(&matches()));
+    job.queue_user(user(3), matches(), score(&matches()));
+
+    let err = job.deliver(&webhooks).await.unwrap_err();
+    assert!(matches!(err, DeliveryError::Unavailable(reason) if reason.contains("404")));
+    assert_eq!(job.pending.len(), 3);
+    assert_eq!(job.stats.lock().unwrap().pending_hits, 3);
+    assert!(job.stats.lock().unwrap().delivery_error.is_some());
+
+    // The batch is rejected, so its hits are sent one by one and the one Discord rejects is dropped
+    job.deliver(&webhooks).await.unwrap();
+    assert!(job.pending.is_empty());
+    assert_eq!(job.stats.lock().unwrap().delivery_error, None);
+    let titles = received
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(_, body)| {
+            body["embeds"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|embed| embed["title"].as_str().unwrap())
+                .collect::<Vec<_>>()
+                .join(",")
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        titles,
+        vec!["user1,user2,user3", "user1,user2,user3", "user1", "user2", "user3"]
+    );
+    let seen = load_seen(job.seen_path.as_ref().unwrap()).unwrap();
+    assert_eq!(seen, vec![SeenEntry::User(1), SeenEntry::User(2), SeenEntry::User(3)]);
+}
+
+#[tokio::test]
+async fn hits_are_batched_in_order_within_discord_limits() {
+    let (url, received) = stub_webhook(vec![]);
+    let webhooks = WebhookClient::new(&url, WebhookProfiles::default());
+
+    let mut job = user_job("batching");
+    for id in 1..=25 {
+        job.queue_user(user(id), matches(), score(&matches()));
+    }
+    job.deliver(&webhooks).await.unwrap();
+    let batches = received
+        .lock()
+        .unwrap()
+        .drain(..)
+        .map(|(_, body)| {
+            body["embeds"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|embed| embed["url"].as_str().unwrap().trim_start_matches("https://anilist.co/user/").parse().unwrap())
+                .collect::<Vec<i32>>()
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(batches, vec![(1..=10).collect::<Vec<_>>(), (11..=20).collect(), (21..=25).collect()]);
+
+    // Five of these come to over 6000 characters
+    for id in 26..=31 {
+        let matches = vec![bio(&format!("{} spam {}", "x".repeat(100), "x".repeat(100))); 7];
+        job.queue_user(user(id), matches.clone(), score(&matches));
+    }
+    job.deliver(&webhooks).await.unwrap();
+    let sizes = received
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(_, body)| body["embeds"].as_array().unwrap().len())
+        .collect::<Vec<_>>();
+    assert_eq!(sizes, vec![4, 2]);
+}
+
+#[tokio::test]
+async fn hits_with_many_matches_are_cut_to_fit_a_message() {
+    let (url, received) = stub_webhook(vec![]);
+    let webhooks = WebhookClient::new(&url, WebhookProfiles::default());
+
+    let mut job = user_job("fitting");
+    let keyword = Keyword::new(KeywordKind::Regex, "x+").unwrap();
+    let text = "x".repeat(400);
+    let matches = (0..24)
+        .map(|_| Match::keyword(&keyword, Target::Bio, &text, 100..300, EntityIds::default()))
+        .collect::<Vec<_>>();
+    job.queue_user(user(1), matches.clone(), score(&matches));
+    job.deliver(&webhooks).await.unwrap();
+
+    let received = received.lock().unwrap();
+    let embed = &received[0].1["embeds"][0];
+    let fields = embed["fields"].as_array().unwrap();
+    let len = |value: &Value| value.as_str().map_or(0, |text| text.chars().count());
+    let text_len = len(&embed["title"])
+        + len(&embed["description"])
+        + len(&embed["author"]["name"])
+        + len(&embed["footer"]["text"])
+        + fields.iter().map(|field| len(&field["name"]) + len(&field["value"])).sum::<usize>();
+    assert!(text_len <= 6000, "embed is {} characters", text_len);
+    assert!(fields.len() < 24);
+    assert_eq!(fields.last().unwrap()["name"], "Score");
+}
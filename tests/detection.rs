@@ -0,0 +1,733 @@
+//! End-to-end detection tests against a stub AniList GraphQL server and a stub
+//! Discord webhook receiver, both bound to localhost.
+
+use al_raid_bot::{
+    anilist::{AniListClient, AniListError, MAX_LIST_PAGES, MAX_REPLY_PAGES},
+    embeds::Severity,
+    matches::*,
+    profiles::{Webhook, WebhookProfiles},
+    webhooks::*,
+};
+use common::{stub_server, Received};
+use serde_json::{json, Value};
+use std::sync::{Arc, Mutex};
+
+mod common;
+
+fn page(items: Value) -> Value {
+    json!({
+        "data": {
+            "Page": {
+                "pageInfo": { "hasNextPage": false },
+                "page": items,
+            }
+        }
+    })
+}
+
+/// Answers each of the bot's queries with a fixed single page of results
+fn stub_anilist() -> (String, Received) {
+    stub_server(|body| {
+        let query = body["query"].as_str().unwrap_or_default();
+        if query.contains("activityReplies") {
+            page(json!([
+                {
+                    "id": 31,
+                    "activityId": 11,
+                    "user": { "id": 3, "name": "replier", "about": null },
+                    "text": "join the raid",
+                }
+            ]))
+        } else if query.contains("activities") {
+            page(json!([
+                {
+                    "activityType": "TextActivity",
+                    "id": 10,
+                    "text": "nothing to see here",
+                    "replyCount": 0,
+                    "user": { "id": 1, "name": "innocent", "about": null },
+                },
+                {
+                    "activityType": "TextActivity",
+                    "id": 11,
+                    "text": "SPAM SPAM SPAM",
+                    "replyCount": 1,
+                    "createdAt": 1609459200,
+                    "user": {
+                        "id": 2,
+                        "name": "spammer",
+                        "about": null,
+                        "avatar": { "medium": "https://img/2-medium.png", "large": "https://img/2-large.png" },
+                    },
+                },
+                {
+                    "activityType": "TextActivity",
+                    "id": 12,
+                    "text": "get ｆr\u{200B}ее n1tr0 here",
+                    "replyCount": 0,
+                    "user": { "id": 4, "name": "sneaky", "about": null },
+                }
+            ]))
+        } else if query.contains("mediaList") {
+            page(json!([
+                { "score": 10.0, "notes": null, "mediaId": 121 }
+            ]))
+        } else if query.contains("users") {
+            page(json!([
+                { "id": 1, "name": "innocent", "about": "hello" },
+                { "id": 2, "name": "raider", "about": "i love spam" }
+            ]))
+        } else {
+            json!({ "data": null, "errors": [{ "message": "unknown query", "status": 400 }] })
+        }
+    })
+}
+
+/// Serves items with IDs `1..=newest`, newest first and two per page, built by `item`
+fn stub_newest_first(newest: Arc<Mutex<i32>>, item: fn(i32) -> Value) -> (String, Received) {
+    stub_server(move |body| {
+        let newest = *newest.lock().unwrap();
+        let page_number = body["variables"]["page"].as_i64().unwrap() as i32;
+        let first = newest - (page_number - 1) * 2;
+        let items = (first - 1..=first).rev().filter(|id| *id > 0).map(item).collect::<Vec<_>>();
+        json!({
+            "data": {
+                "Page": {
+                    "pageInfo": { "hasNextPage": first > 2 },
+                    "page": items,
+                }
+            }
+        })
+    })
+}
+
+fn stub_webhook() -> (String, Received) {
+    stub_server(|_| json!({}))
+}
+
+/// Every embed posted to the webhook, in order, however they were batched into messages
+fn embeds(posted: &Received) -> Vec<Value> {
+    posted
+        .lock()
+        .unwrap()
+        .iter()
+        .flat_map(|(_, body)| body["embeds"].as_array().cloned().unwrap_or_default())
+        .collect()
+}
+
+fn start(job: Value) -> WebhookJob {
+    let mut job: WebhookJob = serde_json::from_value(job).unwrap();
+    job.reset();
+    job
+}
+
+fn activity_job(job: Value) -> Job<ActivityJob> {
+    match start(job) {
+        WebhookJob::Activity(job) => job,
+        _ => panic!("expected an activity job"),
+    }
+}
+
+fn user_job(job: Value) -> Job<UserJob> {
+    match start(job) {
+        WebhookJob::User(job) => job,
+        _ => panic!("expected a user job"),
+    }
+}
+
+#[tokio::test]
+async fn activity_job_posts_flagged_activities_and_replies() {
+    let (anilist_url, _) = stub_anilist();
+    let (webhook_url, posted) = stub_webhook();
+    let anilist = AniListClient::new(&anilist_url);
+    let webhooks = WebhookClient::new(&webhook_url, WebhookProfiles::default());
+
+    let mut job = activity_job(json!({
+        "Activity": {
+            "channelId": "123",
+            "token": "secret",
+            "job": { "keywords": ["spam", "raid"], "replies": true }
+        }
+    }));
+    job.poll(&anilist, &webhooks).await.unwrap();
+
+    let embeds = embeds(&posted);
+    assert_eq!(embeds.len(), 2);
+    // Both hits from one poll go out together
+    let posted = posted.lock().unwrap().clone();
+    assert_eq!(posted.len(), 1);
+    assert_eq!(posted[0].0, "/123/secret");
+
+    let activity = &embeds[0];
+    assert_eq!(activity["title"], "Activity");
+    assert_eq!(activity["url"], "https://anilist.co/activity/11");
+    assert_eq!(activity["fields"][0]["value"], "Matched keyword \"spam\": **SPAM** SPAM SPAM");
+    assert_eq!(activity["description"], "Posted by [spammer](https://anilist.co/user/2)");
+    assert_eq!(activity["author"]["name"], "spammer");
+    assert_eq!(activity["author"]["icon_url"], "https://img/2-medium.png");
+    assert_eq!(activity["thumbnail"]["url"], "https://img/2-large.png");
+    assert_eq!(activity["timestamp"], "2021-01-01T00:00:00Z");
+    assert_eq!(activity["color"], Severity::Low.color());
+    assert_eq!(activity["footer"]["text"], "Severity: low");
+    assert_eq!(activity["fields"][1]["name"], "Score");
+    assert_eq!(activity["fields"][1]["value"], "**1** (reported at 1)\n+1 Activity: keyword \"spam\"");
+
+    let reply = &embeds[1];
+    assert_eq!(reply["title"], "Activity Reply");
+    assert_eq!(reply["url"], "https://anilist.co/activity/11");
+    assert_eq!(reply["fields"][0]["value"], "Matched keyword \"raid\": join the **raid**");
+    assert_eq!(reply["author"]["name"], "replier");
+    assert!(reply.get("thumbnail").is_none());
+}
+
+#[tokio::test]
+async fn activity_job_does_not_repost_the_same_hit() {
+    let (anilist_url, _) = stub_anilist();
+    let (webhook_url, posted) = stub_webhook();
+    let anilist = AniListClient::new(&anilist_url);
+    let webhooks = WebhookClient::new(&webhook_url, WebhookProfiles::default());
+
+    let mut job = activity_job(json!({
+        "Activity": {
+            "channelId": "123",
+            "token": "secret",
+            "job": { "keywords": ["spam"] }
+        }
+    }));
+    job.poll(&anilist, &webhooks).await.unwrap();
+    job.poll(&anilist, &webhooks).await.unwrap();
+
+    assert_eq!(posted.lock().unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn activity_job_pages_back_to_the_last_activity_it_saw() {
+    let newest = Arc::new(Mutex::new(30));
+    let (anilist_url, requests) = stub_newest_first(newest.clone(), |id| {
+        json!({
+            "activityType": "TextActivity",
+            "id": id,
+            "text": if id == 32 { "spam" } else { "hello" },
+            "user": { "id": 1, "name": "someone", "about": null },
+        })
+    });
+    let anilist = AniListClient::new(&anilist_url);
+
+    let mut job = activity_job(json!({
+        "Activity": {
+            "channelId": "123",
+            "token": "secret",
+            "job": { "keywords": ["spam"] }
+        }
+    }));
+
+    // The first poll only reads `depth` pages
+    let hits = job.job.find_activities(&anilist).await.unwrap();
+    assert_eq!(hits.pages, 1);
+    assert_eq!(job.job.newest_activity_id, Some(30));
+
+    // Six activities arrive between polls, so the next poll reads four pages to get back to 30
+    *newest.lock().unwrap() = 36;
+    requests.lock().unwrap().clear();
+    let hits = job.job.find_activities(&anilist).await.unwrap();
+    assert_eq!(hits.pages, 4);
+    assert_eq!(requests.lock().unwrap().len(), 4);
+    let flagged = hits.activities.iter().map(|(activity, _)| activity.id).collect::<Vec<_>>();
+    assert_eq!(flagged, vec![32]);
+    assert_eq!(job.job.newest_activity_id, Some(36));
+
+    // Nothing new, so one page is enough
+    let hits = job.job.find_activities(&anilist).await.unwrap();
+    assert_eq!(hits.pages, 1);
+}
+
+#[tokio::test]
+async fn only_the_newest_replies_and_first_list_entries_are_fetched() {
+    // 25 pages of replies, oldest first, and list entries that never run out
+    let (anilist_url, requests) = stub_server(|body| {
+        let query = body["query"].as_str().unwrap_or_default();
+        let page_number = body["variables"]["page"].as_i64().unwrap();
+        let (items, last_page) = if query.contains("activityReplies") {
+            let reply = json!({
+                "id": page_number,
+                "activityId": 1,
+                "user": { "id": 1, "name": "someone", "about": null },
+                "text": "hi",
+            });
+            (json!([reply]), json!(25))
+        } else {
+            (json!([{ "score": 10.0, "notes": null, "mediaId": 121 }]), Value::Null)
+        };
+        json!({
+            "data": {
+                "Page": {
+                    "pageInfo": { "hasNextPage": page_number < 25, "lastPage": last_page },
+                    "page": items,
+                }
+            }
+        })
+    });
+    let anilist = AniListClient::new(&anilist_url);
+
+    let replies = anilist.query_activity_replies(1).await.unwrap();
+    let ids = replies.iter().map(|reply| reply.id).collect::<Vec<_>>();
+    assert_eq!(ids, (26 - MAX_REPLY_PAGES..=25).collect::<Vec<_>>());
+    // The first page is read to find the last one
+    assert_eq!(requests.lock().unwrap().len(), MAX_REPLY_PAGES as usize + 1);
+
+    requests.lock().unwrap().clear();
+    let entries = anilist.query_in_media_list(1, &[121]).await.unwrap();
+    assert_eq!(entries.len(), MAX_LIST_PAGES as usize);
+    assert_eq!(requests.lock().unwrap().len(), MAX_LIST_PAGES as usize);
+
+    // Short threads are read in full
+    let (short_url, _) = stub_anilist();
+    let replies = AniListClient::new(&short_url).query_activity_replies(11).await.unwrap();
+    assert_eq!(replies.iter().map(|reply| reply.id).collect::<Vec<_>>(), vec![31]);
+}
+
+#[tokio::test]
+async fn activity_job_rechecks_activities_after_a_failed_reply_fetch() {
+    let replies_fail = Arc::new(Mutex::new(true));
+    let fail = replies_fail.clone();
+    let (anilist_url, _) = stub_server(move |body| {
+        let query = body["query"].as_str().unwrap_or_default();
+        if query.contains("activityReplies") {
+            if *fail.lock().unwrap() {
+                json!({ "data": null, "errors": [{ "message": "Internal Server Error", "status": 500 }] })
+            } else {
+                page(json!([]))
+            }
+        } else {
+            page(json!([
+                {
+                    "activityType": "TextActivity",
+                    "id": 21,
+                    "text": "spam",
+                    "user": { "id": 2, "name": "spammer", "about": null },
+                },
+                {
+                    "activityType": "TextActivity",
+                    "id": 20,
+                    "text": "hello",
+                    "replyCount": 1,
+                    "user": { "id": 1, "name": "someone", "about": null },
+                }
+            ]))
+        }
+    });
+    let anilist = AniListClient::new(&anilist_url);
+    let mut job = activity_job(json!({
+        "Activity": {
+            "channelId": "123",
+            "token": "secret",
+            "job": { "keywords": ["spam"], "replies": true }
+        }
+    }));
+
+    // Activity 21 is flagged before the replies of 20 fail, so neither the hit nor the mark is kept
+    assert!(job.job.find_activities(&anilist).await.is_err());
+    assert_eq!(job.job.newest_activity_id, None);
+
+    *replies_fail.lock().unwrap() = false;
+    let hits = job.job.find_activities(&anilist).await.unwrap();
+    let flagged = hits.activities.iter().map(|(activity, _)| activity.id).collect::<Vec<_>>();
+    assert_eq!(flagged, vec![21]);
+    assert_eq!(job.job.newest_activity_id, Some(21));
+
+    // Once reported, the hit isn't found again
+    job.job.newest_activity_id = None;
+    assert!(job.job.find_activities(&anilist).await.unwrap().activities.is_empty());
+}
+
+#[tokio::test]
+async fn job_posts_to_a_named_webhook_profile() {
+    let (anilist_url, _) = stub_anilist();
+    let (webhook_url, posted) = stub_webhook();
+    let anilist = AniListClient::new(&anilist_url);
+    let profiles = WebhookProfiles::default();
+    profiles
+        .insert("mod-log", Webhook::parse("https://discord.com/api/webhooks/789/hidden").unwrap())
+        .unwrap();
+    let webhooks = WebhookClient::new(&webhook_url, profiles.clone());
+
+    let mut job = start(json!({
+        "Activity": {
+            "webhook": "mod-log",
+            "job": { "keywords": ["spam"] }
+        }
+    }));
+    job.validate(&profiles).unwrap();
+    job.poll(&anilist, &webhooks).await.unwrap();
+    assert_eq!(posted.lock().unwrap()[0].0, "/789/hidden");
+
+    let unknown = start(json!({
+        "Activity": {
+            "webhook": "elsewhere",
+            "job": { "keywords": ["spam"] }
+        }
+    }));
+    assert!(unknown.validate(&profiles).is_err());
+}
+
+#[tokio::test]
+async fn restored_job_skips_hits_reported_before_a_restart() {
+    let (anilist_url, _) = stub_anilist();
+    let (webhook_url, posted) = stub_webhook();
+    let anilist = AniListClient::new(&anilist_url);
+    let webhooks = WebhookClient::new(&webhook_url, WebhookProfiles::default());
+    let data_dir = std::env::temp_dir().join(format!("al-raid-bot-test-{}", std::process::id()));
+
+    for _ in 0..2 {
+        let mut job = start(json!({
+            "Activity": {
+                "channelId": "123",
+                "token": "secret",
+                "job": { "keywords": ["spam", "raid"], "replies": true }
+            }
+        }));
+        job.restore(&data_dir).unwrap();
+        job.poll(&anilist, &webhooks).await.unwrap();
+    }
+    std::fs::remove_dir_all(&data_dir).unwrap();
+
+    assert_eq!(embeds(&posted).len(), 2);
+}
+
+#[tokio::test]
+async fn activity_job_matches_whole_words_and_regexes() {
+    let (anilist_url, _) = stub_anilist();
+    let (webhook_url, posted) = stub_webhook();
+    let anilist = AniListClient::new(&anilist_url);
+    let webhooks = WebhookClient::new(&webhook_url, WebhookProfiles::default());
+
+    let mut job = activity_job(json!({
+        "Activity": {
+            "channelId": "123",
+            "token": "secret",
+            "job": { "keywords": [{ "word": "spa" }, { "regex": "(spam\\s*){3}" }] }
+        }
+    }));
+    job.poll(&anilist, &webhooks).await.unwrap();
+
+    let posted = posted.lock().unwrap().clone();
+    assert_eq!(posted.len(), 1);
+    let fields = posted[0].1["embeds"][0]["fields"].as_array().unwrap().clone();
+    assert_eq!(fields.len(), 2);
+    assert_eq!(fields[0]["value"], "Matched regex /(spam\\s*){3}/: **SPAM SPAM SPAM**");
+}
+
+#[tokio::test]
+async fn hits_are_reported_and_escalated_by_score() {
+    let (anilist_url, _) = stub_anilist();
+    let (webhook_url, posted) = stub_webhook();
+    let anilist = AniListClient::new(&anilist_url);
+    let webhooks = WebhookClient::new(&webhook_url, WebhookProfiles::default());
+
+    let mut job = start(json!({
+        "Activity": {
+            "channelId": "123",
+            "token": "secret",
+            "job": {
+                "keywords": [{ "literal": "spam", "weight": 3 }, "raid"],
+                "replies": true,
+                "reportScore": 2,
+                "escalateScore": 3
+            }
+        }
+    }));
+    // Weights survive being saved to `tasks.json`
+    let saved = serde_json::to_value(&job).unwrap();
+    assert_eq!(saved["Activity"]["job"]["keywords"], json!([{ "literal": "spam", "weight": 3 }, "raid"]));
+    job.poll(&anilist, &webhooks).await.unwrap();
+
+    // The reply only matched "raid", which is worth 1 and so not reported
+    let embeds = embeds(&posted);
+    assert_eq!(embeds.len(), 1);
+    let activity = &embeds[0];
+    assert_eq!(activity["url"], "https://anilist.co/activity/11");
+    assert_eq!(activity["color"], Severity::High.color());
+    assert_eq!(activity["footer"]["text"], "Escalated, severity: high");
+    assert_eq!(
+        activity["fields"][1]["value"],
+        "**3** (reported at 2, escalated at 3)\n+3 Activity: keyword \"spam\""
+    );
+    let stats = job.stats().lock().unwrap().clone();
+    assert_eq!((stats.items_flagged, stats.items_escalated), (1, 1));
+}
+
+#[tokio::test]
+async fn activity_job_normalizes_lookalikes_when_enabled() {
+    let (anilist_url, _) = stub_anilist();
+    let (webhook_url, posted) = stub_webhook();
+    let anilist = AniListClient::new(&anilist_url);
+    let webhooks = WebhookClient::new(&webhook_url, WebhookProfiles::default());
+
+    for (normalize, expected) in [(false, 0), (true, 1)].iter() {
+        let mut job = activity_job(json!({
+            "Activity": {
+                "channelId": "123",
+                "token": "secret",
+                "job": { "keywords": ["free nitro"], "normalize": normalize }
+            }
+        }));
+        job.poll(&anilist, &webhooks).await.unwrap();
+        assert_eq!(posted.lock().unwrap().len(), *expected);
+    }
+
+    let posted = posted.lock().unwrap().clone();
+    let embed = &posted[0].1["embeds"][0];
+    assert_eq!(embed["url"], "https://anilist.co/activity/12");
+    assert_eq!(embed["fields"][0]["value"], "Matched keyword \"free nitro\": get **ｆr\u{200B}ее n1tr0** here");
+}
+
+#[tokio::test]
+async fn regex_keywords_match_the_original_text_when_normalizing() {
+    let (anilist_url, _) = stub_anilist();
+    let anilist = AniListClient::new(&anilist_url);
+    let mut job = activity_job(json!({
+        "Activity": {
+            "channelId": "123",
+            "token": "secret",
+            "job": { "keywords": [{ "regex": "n\\d+" }, "nitro"], "normalize": true }
+        }
+    }));
+
+    let hits = job.job.find_activities(&anilist).await.unwrap();
+    let flagged = hits
+        .activities
+        .iter()
+        .map(|(activity, matches)| {
+            let matched = matches.iter().map(|m| m.span.as_ref().unwrap().matched().to_string());
+            (activity.id, matched.collect::<Vec<_>>())
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(flagged, vec![(12, vec!["n1".to_string(), "n1tr0".to_string()])]);
+}
+
+#[tokio::test]
+async fn user_job_posts_flagged_users() {
+    let (anilist_url, queries) = stub_anilist();
+    let (webhook_url, posted) = stub_webhook();
+    let anilist = AniListClient::new(&anilist_url);
+    let webhooks = WebhookClient::new(&webhook_url, WebhookProfiles::default());
+
+    let mut job = user_job(json!({
+        "User": {
+            "channelId": "456",
+            "token": "secret",
+            "job": {
+                "keywords": ["spam"],
+                "mediaIds": [121],
+                "depth": 1,
+                "maxScoreThreshold": 30
+            }
+        }
+    }));
+    job.poll(&anilist, &webhooks).await.unwrap();
+
+    // One users page plus a list lookup for each user
+    assert_eq!(queries.lock().unwrap().len(), 3);
+    let stats = job.stats.lock().unwrap().clone();
+    assert_eq!((stats.pages_scanned, stats.items_checked, stats.items_flagged), (1, 2, 2));
+    assert!(stats.last_error.is_none());
+
+    let embeds = embeds(&posted);
+    assert_eq!(embeds.len(), 2);
+    assert_eq!(posted.lock().unwrap()[0].0, "/456/secret");
+
+    let innocent = &embeds[0];
+    assert_eq!(innocent["title"], "innocent");
+    assert_eq!(innocent["fields"][0]["name"], "List Entry Score");
+
+    let raider = &embeds[1];
+    assert_eq!(raider["title"], "raider");
+    assert_eq!(raider["url"], "https://anilist.co/user/2");
+    assert_eq!(raider["fields"][0]["value"], "Bio matched keyword \"spam\": i love **spam**");
+    // Two signals make it a medium severity hit
+    assert_eq!(raider["color"], Severity::Medium.color());
+    assert_eq!(raider["fields"][1]["name"], "List Entry Score");
+}
+
+#[tokio::test]
+async fn user_job_only_sweeps_users_newer_than_the_last_sweep() {
+    let newest = Arc::new(Mutex::new(20));
+    let (anilist_url, requests) = stub_newest_first(newest.clone(), |id| {
+        json!({ "id": id, "name": format!("user{}", id), "about": if id == 21 { "spam" } else { "hi" } })
+    });
+    let anilist = AniListClient::new(&anilist_url);
+    let sweeper = |full_sweep: bool| user_job(json!({
+        "User": {
+            "channelId": "123",
+            "token": "secret",
+            "job": { "keywords": ["spam"], "mediaIds": [], "depth": 5, "fullSweep": full_sweep }
+        }
+    }));
+
+    // Without a full sweep, the first sweep only reads the newest page
+    let mut job = sweeper(false);
+    let hits = job.job.find_users(&anilist).await.unwrap();
+    assert_eq!((hits.pages, hits.checked), (1, 2));
+    assert_eq!(job.job.newest_user_id, Some(20));
+
+    let mut full = sweeper(true);
+    let hits = full.job.find_users(&anilist).await.unwrap();
+    assert_eq!((hits.pages, hits.checked), (5, 10));
+
+    // Three users sign up, so only they are checked
+    *newest.lock().unwrap() = 23;
+    requests.lock().unwrap().clear();
+    let hits = job.job.find_users(&anilist).await.unwrap();
+    assert_eq!((hits.pages, hits.checked), (2, 3));
+    assert_eq!(requests.lock().unwrap().len(), 2);
+    let flagged = hits.users.iter().map(|(user, _)| user.id).collect::<Vec<_>>();
+    assert_eq!(flagged, vec![21]);
+    assert_eq!(job.job.newest_user_id, Some(23));
+
+    // The full sweep job catches up the same way
+    let hits = full.job.find_users(&anilist).await.unwrap();
+    assert_eq!((hits.pages, hits.checked), (2, 3));
+
+    let hits = job.job.find_users(&anilist).await.unwrap();
+    assert_eq!((hits.pages, hits.checked), (1, 0));
+}
+
+#[tokio::test]
+async fn private_lists_are_reported_without_aborting_the_sweep() {
+    let (anilist_url, _) = stub_server(|body| {
+        let query = body["query"].as_str().unwrap_or_default();
+        if query.contains("mediaList") {
+            if body["variables"]["userId"] == 1 {
+                json!({ "data": null, "errors": [{ "message": "Private User", "status": 404 }] })
+            } else {
+                page(json!([{ "score": 1.0, "notes": null, "mediaId": 121 }]))
+            }
+        } else {
+            page(json!([
+                { "id": 2, "name": "lowballer", "about": null },
+                { "id": 1, "name": "hidden", "about": "spam" }
+            ]))
+        }
+    });
+    let anilist = AniListClient::new(&anilist_url);
+    let mut job = user_job(json!({
+        "User": {
+            "channelId": "123",
+            "token": "secret",
+            "job": { "keywords": ["spam"], "mediaIds": [121], "depth": 1, "maxScoreThreshold": 3 }
+        }
+    }));
+
+    let hits = job.job.find_users(&anilist).await.unwrap();
+    assert_eq!(hits.private_lists, vec![1]);
+    let flagged = hits
+        .users
+        .iter()
+        .map(|(user, matches)| {
+            let matches = matches.iter().map(|m| (m.target, m.rule.to_string(), m.ids.clone(), m.weight));
+            (user.id, matches.collect::<Vec<_>>())
+        })
+        .collect::<Vec<_>>();
+    let ids = |user_id, media_id| EntityIds {
+        user_id,
+        media_id,
+        ..EntityIds::default()
+    };
+    assert_eq!(
+        flagged,
+        vec![
+            (2, vec![(Target::ListEntry, "list score at or below 3".to_string(), ids(2, Some(121)), 1)]),
+            (
+                1,
+                vec![
+                    (Target::Bio, "keyword \"spam\"".to_string(), ids(1, None), 1),
+                    (Target::ListEntry, "private list".to_string(), ids(1, None), 0),
+                ]
+            ),
+        ]
+    );
+
+    // Matches only turn into text when they are put on an embed
+    let hidden = &hits.users[1].1;
+    assert_eq!(hidden[0].span.as_ref().unwrap().matched(), "spam");
+    assert_eq!(hidden[0].field().value, "Bio matched keyword \"spam\": **spam**");
+    assert_eq!(hidden[1].field().value, "List is private, scores could not be checked");
+    assert_eq!(hits.users[0].1[0].field().value, "Media ID poorly scored: 121");
+}
+
+#[tokio::test]
+async fn user_job_rechecks_users_after_a_failed_list_query() {
+    let lists_fail = Arc::new(Mutex::new(true));
+    let fail = lists_fail.clone();
+    let (anilist_url, _) = stub_server(move |body| {
+        let query = body["query"].as_str().unwrap_or_default();
+        if query.contains("mediaList") {
+            if *fail.lock().unwrap() && body["variables"]["userId"] == 1 {
+                json!({ "data": null, "errors": [{ "message": "Internal Server Error", "status": 500 }] })
+            } else {
+                page(json!([]))
+            }
+        } else {
+            page(json!([
+                { "id": 2, "name": "raider", "about": "spam" },
+                { "id": 1, "name": "someone", "about": null }
+            ]))
+        }
+    });
+    let anilist = AniListClient::new(&anilist_url);
+    let mut job = user_job(json!({
+        "User": {
+            "channelId": "123",
+            "token": "secret",
+            "job": { "keywords": ["spam"], "mediaIds": [121], "depth": 1 }
+        }
+    }));
+
+    // User 2 is flagged before the sweep fails on user 1, so neither the hit nor the mark is kept
+    assert!(job.job.find_users(&anilist).await.is_err());
+    assert_eq!(job.job.newest_user_id, None);
+
+    *lists_fail.lock().unwrap() = false;
+    let hits = job.job.find_users(&anilist).await.unwrap();
+    assert_eq!(hits.checked, 2);
+    let flagged = hits.users.iter().map(|(user, _)| user.id).collect::<Vec<_>>();
+    assert_eq!(flagged, vec![2]);
+    assert_eq!(job.job.newest_user_id, Some(2));
+
+    // Once reported, the hit isn't found again
+    job.job.newest_user_id = None;
+    assert!(job.job.find_users(&anilist).await.unwrap().users.is_empty());
+}
+
+#[tokio::test]
+async fn rejected_queries_stop_the_job_but_server_errors_do_not() {
+    let (rejecting_url, _) = stub_server(|_| {
+        json!({ "data": null, "errors": [{ "message": "Validation error", "status": 400 }] })
+    });
+    let (failing_url, _) = stub_server(|_| {
+        json!({ "data": null, "errors": [{ "message": "Internal Server Error", "status": 500 }] })
+    });
+    let webhooks = WebhookClient::default();
+    let job = || activity_job(json!({
+        "Activity": {
+            "channelId": "123",
+            "token": "secret",
+            "job": { "keywords": ["spam"] }
+        }
+    }));
+
+    let rejecting = AniListClient::new(&rejecting_url);
+    assert!(matches!(
+        rejecting.query_activities(1, 1, None).await,
+        Err(AniListError::InvalidQuery(message)) if message == "Validation error"
+    ));
+    let mut rejected = job();
+    assert!(rejected.poll(&rejecting, &webhooks).await.is_err());
+    assert!(rejected.stopped());
+
+    let failing = AniListClient::new(&failing_url);
+    assert!(matches!(failing.query_users(1, 1, None).await, Err(AniListError::Server(_))));
+    let mut failed = job();
+    assert!(failed.poll(&failing, &webhooks).await.is_err());
+    assert!(!failed.stopped());
+}
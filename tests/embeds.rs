@@ -0,0 +1,33 @@
+//! Snippets and timestamps shown in hit embeds
+
+use al_raid_bot::{
+    embeds::{snippet, EmbedField, MAX_FIELD_VALUE_LEN},
+    util::iso8601,
+};
+
+#[test]
+fn snippets_highlight_the_match_and_trim_what_is_around_it() {
+    let text = format!("{} buy *cheap* followers\nnow {}", "a".repeat(100), "b".repeat(100));
+    let start = text.find("*cheap*").unwrap();
+    let snippet = snippet(&text, start..start + "*cheap*".len());
+    assert_eq!(
+        snippet,
+        format!("…{} buy **\\*cheap\\*** followers now {}…", "a".repeat(56), "b".repeat(46))
+    );
+}
+
+#[test]
+fn long_matches_still_fit_in_a_field() {
+    let text = "spam ".repeat(1000);
+    let value = format!("Matched regex /(spam )+/: {}", snippet(&text, 0..text.len()));
+    let field = EmbedField::from("Activity".to_string(), value);
+    assert!(field.value.chars().count() <= MAX_FIELD_VALUE_LEN);
+    assert!(field.value.ends_with("…**"));
+}
+
+#[test]
+fn timestamps_are_formatted_as_iso_8601() {
+    assert_eq!(iso8601(0), "1970-01-01T00:00:00Z");
+    assert_eq!(iso8601(951_825_600), "2000-02-29T12:00:00Z");
+    assert_eq!(iso8601(1_609_459_199), "2020-12-31T23:59:59Z");
+}
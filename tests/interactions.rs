@@ -0,0 +1,133 @@
+//! Slash command handling against a stub of Discord's interactions endpoints
+
+use al_raid_bot::{
+    commands::{Reply, StopTarget},
+    interactions::{Interaction, InteractionsClient, RaidCommand},
+    webhooks::WebhookJob,
+};
+use common::stub_server;
+use serde_json::{json, Value};
+
+mod common;
+
+fn interaction(subcommand: &str, options: Value) -> Interaction {
+    serde_json::from_value(json!({
+        "id": "900",
+        "type": 2,
+        "token": "interaction-token",
+        "channel_id": "55",
+        "guild_id": "66",
+        "member": {
+            "user": { "id": "77", "username": "mod", "discriminator": "0001" },
+            "roles": ["88"],
+        },
+        "data": {
+            "name": "raid",
+            "options": [{ "name": subcommand, "type": 1, "options": options }],
+        },
+    }))
+    .unwrap()
+}
+
+#[test]
+fn slash_options_become_commands() {
+    let start = interaction(
+        "start",
+        json!([
+            { "name": "target", "type": 3, "value": "users" },
+            { "name": "webhook", "type": 3, "value": "mod-log" },
+            { "name": "keywords", "type": 3, "value": "spam,word:raid" },
+            { "name": "depth", "type": 4, "value": 5 },
+            { "name": "max-score", "type": 4, "value": 20 },
+        ]),
+    );
+    assert!(start.is_raid_command());
+    assert_eq!(start.command_name(), "/raid start");
+    assert_eq!(start.role_ids(), vec![88]);
+    assert_eq!(start.channel_id(), Some(55));
+    match start.command() {
+        Ok(RaidCommand::Start(job)) => match *job {
+            WebhookJob::User(job) => {
+                assert_eq!(job.webhook.as_deref(), Some("mod-log"));
+                assert_eq!(job.job.depth, 5);
+                assert_eq!(job.job.max_score_threshold, Some(20));
+                assert_eq!(job.job.keywords.len(), 2);
+            }
+            _ => panic!("expected a user job"),
+        },
+        _ => panic!("expected a start command"),
+    }
+
+    let bad = interaction(
+        "start",
+        json!([
+            { "name": "target", "type": 3, "value": "activities" },
+            { "name": "webhook", "type": 3, "value": "mod-log" },
+        ]),
+    );
+    assert!(matches!(bad.command(), Err(err) if err.message == "`keywords` is required."));
+
+    let stop = interaction("stop", json!([{ "name": "id", "type": 4, "value": 3 }]));
+    assert!(matches!(stop.command(), Ok(RaidCommand::Stop(Some(StopTarget::One(3))))));
+    let stop_all = interaction("stop", json!([{ "name": "all", "type": 5, "value": true }]));
+    assert!(matches!(stop_all.command(), Ok(RaidCommand::Stop(Some(StopTarget::All)))));
+    assert!(matches!(interaction("stop", json!([])).command(), Ok(RaidCommand::Stop(None))));
+    assert!(matches!(interaction("status", json!([])).command(), Ok(RaidCommand::Status)));
+}
+
+#[tokio::test]
+async fn commands_are_registered_and_answered() {
+    let (url, received) = stub_server(|_| json!({}));
+    let client = InteractionsClient::new(&url, "123", "bot-token");
+
+    client.register_commands(Some("66")).await.unwrap();
+    client
+        .respond(&interaction("status", json!([])), &Reply::Info("No tasks are running.".to_string()))
+        .await
+        .unwrap();
+    client
+        .respond(&interaction("status", json!([])), &Reply::error("Not allowed", "Nope."))
+        .await
+        .unwrap();
+
+    let received = received.lock().unwrap();
+    assert_eq!(received.len(), 3);
+
+    let (path, body) = &received[0];
+    assert_eq!(path, "/applications/123/guilds/66/commands");
+    assert_eq!(body[0]["name"], "raid");
+    let subcommands = body[0]["options"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|option| option["name"].as_str().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(subcommands, vec!["start", "stop", "status"]);
+
+    let (path, body) = &received[1];
+    assert_eq!(path, "/interactions/900/interaction-token/callback");
+    assert_eq!(body["type"], 4);
+    assert_eq!(body["data"]["embeds"][0]["description"], "No tasks are running.");
+
+    let (_, body) = &received[2];
+    assert_eq!(body["data"]["embeds"][0]["title"], "Not allowed");
+    assert!(body["data"]["embeds"][0]["color"].is_u64());
+}
+
+#[tokio::test]
+async fn slow_commands_are_deferred_then_answered() {
+    let (url, received) = stub_server(|_| json!({}));
+    let client = InteractionsClient::new(&url, "123", "bot-token");
+    let stop = interaction("stop", json!([{ "name": "all", "type": 5, "value": true }]));
+
+    client.defer(&stop).await.unwrap();
+    client.edit_response(&stop, &Reply::Info("Stopped 2 task(s).".to_string())).await.unwrap();
+
+    let received = received.lock().unwrap();
+    let (path, body) = &received[0];
+    assert_eq!(path, "/interactions/900/interaction-token/callback");
+    assert_eq!(body["type"], 5);
+    let (path, body) = &received[1];
+    assert_eq!(path, "/webhooks/123/interaction-token/messages/@original");
+    assert_eq!(body["embeds"][0]["description"], "Stopped 2 task(s).");
+}
@@ -0,0 +1,38 @@
+use al_raid_bot::ratelimit::{RateLimitUsage, RateLimiter};
+use std::time::{Duration, Instant};
+
+#[tokio::test]
+async fn requests_are_paced_once_the_bucket_is_empty() {
+    // Two requests per second, refilled one every half second
+    let limiter = RateLimiter::new(2, Duration::from_secs(1));
+    let started = Instant::now();
+    limiter.acquire().await;
+    limiter.clone().acquire().await;
+    assert!(started.elapsed() < Duration::from_millis(100));
+
+    limiter.acquire().await;
+    assert!(started.elapsed() >= Duration::from_millis(450));
+}
+
+#[tokio::test]
+async fn server_reports_shrink_the_budget() {
+    let limiter = RateLimiter::new(90, Duration::from_secs(60));
+    limiter.update(Some(30), Some(2));
+    assert_eq!(
+        limiter.usage(),
+        RateLimitUsage {
+            limit: 30,
+            available: 2,
+            reported_remaining: Some(2),
+        }
+    );
+
+    limiter.update(None, Some(0));
+    assert_eq!(limiter.usage().available, 0);
+
+    // 30 a minute is one every two seconds
+    limiter.update(Some(30), None);
+    let started = Instant::now();
+    limiter.acquire().await;
+    assert!(started.elapsed() >= Duration::from_millis(1900));
+}
@@ -0,0 +1,114 @@
+use al_raid_bot::{config::Config, profiles::WebhookProfiles, tasks::TaskRegistry, util, webhooks::WebhookJob};
+use common::stub_server;
+use serde_json::json;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+mod common;
+
+#[tokio::test]
+async fn running_tasks_are_resumed_by_a_new_registry() {
+    let config = Config {
+        // Nothing listens here, so the spawned tasks just log connection errors
+        anilist_url: "http://127.0.0.1:9".to_string(),
+        webhook_url: "http://127.0.0.1:9".to_string(),
+        data_dir: std::env::temp_dir().join(format!("al-raid-bot-tasks-{}", std::process::id())),
+        ..Config::default()
+    };
+    let job: WebhookJob = serde_json::from_value(json!({
+        "Activity": {
+            "channelId": "123",
+            "token": "secret",
+            "job": { "keywords": ["spam", { "word": "raid" }, { "regex": "free\\s+nitro" }] }
+        }
+    }))
+    .unwrap();
+
+    let (notices, _) = mpsc::unbounded_channel();
+    let mut before = TaskRegistry::new(&config, WebhookProfiles::default(), notices.clone());
+    let first = before.start(job.clone(), 42).unwrap();
+    let second = before.start(job, 43).unwrap();
+    assert!(before.stop(first).await);
+
+    let mut after = TaskRegistry::new(&config, WebhookProfiles::default(), notices.clone());
+    assert_eq!(after.resume().unwrap(), vec![(second, 43)]);
+    let (_, task) = after.iter().next().unwrap();
+    match &task.job {
+        WebhookJob::Activity(job) => {
+            assert_eq!(job.token.as_deref(), Some("secret"));
+            let keywords = job.job.keywords.iter().map(|k| k.to_string()).collect::<Vec<_>>();
+            assert_eq!(keywords, vec!["keyword \"spam\"", "word \"raid\"", "regex /free\\s+nitro/"]);
+        }
+        _ => unreachable!(),
+    }
+
+    let status = after.status();
+    assert!(status.contains(&format!("**Task {}**: watching activities", second)));
+    assert!(status.contains("Keywords: keyword \"spam\", word \"raid\""));
+
+    // New tasks carry on from the highest resumed ID
+    let third = after.start(task.job.clone(), 44).unwrap();
+    assert!(third > second);
+
+    before.stop_all().await;
+    after.stop_all().await;
+    std::fs::remove_dir_all(&config.data_dir).unwrap();
+}
+
+#[tokio::test]
+async fn a_task_whose_query_is_rejected_tells_its_channel() {
+    let (anilist_url, _) = stub_server(|_| {
+        json!({ "data": null, "errors": [{ "message": "Validation error", "status": 400 }] })
+    });
+    let config = Config {
+        anilist_url,
+        data_dir: std::env::temp_dir().join(format!("al-raid-bot-rejected-{}", std::process::id())),
+        ..Config::default()
+    };
+    let job: WebhookJob = serde_json::from_value(json!({
+        "Activity": {
+            "channelId": "123",
+            "token": "secret",
+            "job": { "keywords": ["spam"], "interval": 0, "jitter": 0 }
+        }
+    }))
+    .unwrap();
+
+    let (notices, mut received) = mpsc::unbounded_channel();
+    let mut tasks = TaskRegistry::new(&config, WebhookProfiles::default(), notices);
+    let id = tasks.start(job, 42).unwrap();
+    let notice = tokio::time::timeout(Duration::from_secs(5), received.recv()).await.unwrap().unwrap();
+    assert_eq!((notice.task, notice.channel_id), (id, 42));
+    assert!(notice.message.contains("rejected its query"));
+    assert!(notice.message.contains("Validation error"));
+
+    tasks.stop_all().await;
+    let _ = std::fs::remove_dir_all(&config.data_dir);
+}
+
+#[tokio::test]
+async fn intervals_longer_than_a_day_are_rejected() {
+    let config = Config {
+        data_dir: std::env::temp_dir().join(format!("al-raid-bot-interval-{}", std::process::id())),
+        ..Config::default()
+    };
+    let (notices, _) = mpsc::unbounded_channel();
+    let mut tasks = TaskRegistry::new(&config, WebhookProfiles::default(), notices);
+    for (interval, jitter) in [(u64::MAX, 0), (0, u64::MAX)].iter() {
+        let job: WebhookJob = serde_json::from_value(json!({
+            "Activity": {
+                "channelId": "123",
+                "token": "secret",
+                "job": { "keywords": ["spam"], "interval": interval, "jitter": jitter }
+            }
+        }))
+        .unwrap();
+        let err = tasks.start(job, 42).unwrap_err();
+        assert!(err.to_string().contains("at most 86400 seconds"));
+    }
+
+    // Out of range values still don't overflow
+    assert_eq!(util::with_jitter(u64::MAX, 0), Duration::from_secs(u64::MAX));
+    util::with_jitter(u64::MAX, u64::MAX);
+    let _ = std::fs::remove_dir_all(&config.data_dir);
+}